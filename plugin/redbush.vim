@@ -17,6 +17,21 @@ call s:config('filepath', '/tmp/redbush-eval.clj')
 call s:config('filesize', 1000)
 call s:config('is_vertical', 1)
 call s:config('winsize', 40)
+call s:config('trace_filter', ['clojure', 'java', 'tooling', 'dup'])
+call s:config('source_paths', ['src', 'test'])
+call s:config('ansi', 'highlight')
+call s:config('output_filter', {})
+call s:config('output_buffer', 0)
+call s:config('output_filesize', 1000)
+call s:config('output_winsize', 15)
+
+" entries of the evaluation buffer, RedBushValue is left undefined to keep
+" the syntax highlighting of values
+highlight default link RedBushOut Normal
+highlight default link RedBushErr ErrorMsg
+highlight default link RedBushException WarningMsg
+highlight default link RedBushTiming Comment
+highlight default link RedBushSystem Comment
 
 
 """"""""""""""""""""""
@@ -93,12 +108,20 @@ fun! s:logbuf_show(is_vertical, size)
     endif
     normal <C-End>
     exe 'set signcolumn=no'
+    setlocal foldmethod=expr foldexpr=RedBushFoldLevel(v:lnum) foldlevel=99
     exe g:redbush_filesize
     let s:logbuf_bufinfo = getbufinfo(g:redbush_filepath)[0]
     let g:logbuf_winid = bufwinid(g:redbush_filepath)
     exe prev_window . 'wincmd w'
 endf
 
+" entries of the log buffers start at extmarks of the redbush_entries namespace
+fun! RedBushFoldLevel(lnum)
+    let ns = nvim_create_namespace('redbush_entries')
+    let pos = [a:lnum - 1, 0]
+    return empty(nvim_buf_get_extmarks(0, ns, pos, pos, {})) ? '1' : '>1'
+endf
+
 fun! s:logbuf_hide()
     for winid in get(s:logbuf_bufinfo, 'windows',[])
         let winfo = getwininfo(winid)
@@ -152,41 +175,52 @@ fun! s:selected_text()
     return join(lines, "\n")                 
 endf
 
+fun! s:buffer_text() 
+    return join(getline(1, '$'), "\n")
+endf
+
 fun! s:eval_range() 
     let [line, column] = getpos("'<")[1:2]
     let args = {
         \ "file": expand("%:p"),
         \ "line": line, 
         \ "column": column,
+        \ "buffer": s:buffer_text(),
         \ "code": s:selected_text() }
     call s:send_to_plugin('eval', args)
 endf
 
+fun! s:eval_at_cursor(scope, ...) 
+    let [line, column] = getpos('.')[1:2]
+    let args = {
+        \ "file": expand("%:p"),
+        \ "cursor-line": line, 
+        \ "cursor-column": column,
+        \ "scope": a:scope,
+        \ "buffer": s:buffer_text() }
+    if a:0 == 1
+        let args["wrap"] = a:1
+    endif
+    call s:send_to_plugin('eval-form', args)
+endf
+
 fun! s:eval_form() 
-    normal va(
-    normal "_y 
-    call s:eval_range()
+    call s:eval_at_cursor('form')
+endf
+
+fun! s:eval_top_form() 
+    call s:eval_at_cursor('top')
+endf
+
+fun! s:eval_form_before() 
+    call s:eval_at_cursor('before')
 endf
 
 fun! s:eval_form_time() 
-    let [line, column] = getpos("'<")[1:2]
-    normal va(
-    normal "_y 
-    let args = {
-        \ "file": expand("%:p"),
-        \ "line": line, 
-        \ "column": column,
-        \ "code": '(time ' . s:selected_text() . " \n)" }
-    call s:send_to_plugin('eval', args)
+    call s:eval_at_cursor('form', 'clojure.core/time')
 endf
 
 fun! s:eval_file() 
-    let lines = ''
-    for l in    getline(1, line("$"))
-        let l = trim(l)
-        let lines = lines . "\n" . l
-    endfor
-
     let splits = split(expand("%:p"), 'src/') 
     if len(splits) == 2
         let l:path = splits[1]
@@ -194,24 +228,125 @@ fun! s:eval_file()
         let l:path = expand("%:t")
     endif
 
-    let end = getpos("$")[1] 
-    let lines = getline(1, end)                 
     let args = {
-        \ "line": 0,
-        \ "code": join(lines, "\n"),
+        \ "file": s:buffer_text(),
         \ "file-path": l:path, 
         \ "file-name": expand("%:t") }
 
-    call s:send_to_plugin('eval', args)
+    call s:send_to_plugin('load-file', args)
 endf
 
 fun! s:run_tests() 
     let args = {
         \ "file": expand("%:p"),
+        \ "buffer": s:buffer_text(),
         \ "code": '(clojure.test/run-tests)' }
     call s:send_to_plugin('eval', args)
 endf
 
+fun! s:quickfix() 
+    call s:send_to_plugin('quickfix', {})
+endf
+
+fun! s:last_exception() 
+    call s:send_to_plugin('last-exception', {})
+endf
+
+fun! s:goto_frame() 
+    let frame = get(s:exception_frames, line('.'), -1)
+    if frame >= 0
+        call s:send_to_plugin('goto-frame', {"frame": frame + 1})
+    endif
+endf
+
+fun! s:show_exception(lines, frames) 
+    let s:exception_frames = {}
+    for [lnum, frame] in a:frames
+        let s:exception_frames[lnum] = frame
+    endfor
+
+    let winid = bufwinid('redbush://exception')
+    if winid == -1
+        exe 'botright split redbush://exception'
+    else
+        call win_gotoid(winid)
+    endif
+    setlocal buftype=nofile bufhidden=hide noswapfile nobuflisted
+    setlocal modifiable noreadonly
+    setlocal foldmethod=indent foldlevel=1 shiftwidth=2
+    silent %delete _
+    call setline(1, a:lines)
+    setlocal nomodifiable readonly
+    nnoremap <buffer> <silent> <CR> :call <SID>goto_frame()<CR>
+endf
+
+fun! RedBushShowException(lines, frames) 
+    call timer_start(0, {-> s:show_exception(a:lines, a:frames)})
+endf
+
+fun! s:read_input() 
+    let cancel = "\<Esc>"
+    let answer = input({'prompt': 'Input: ', 'cancelreturn': cancel})
+    if answer ==# cancel
+        " empty stdin is end of input
+        call s:send_to_plugin('stdin', {"stdin": ""})
+    else
+        call s:send_to_plugin('stdin', {"stdin": answer . "\n"})
+    endif
+endf
+
+fun! RedBushNeedInput() 
+    call timer_start(0, {-> s:read_input()})
+endf
+
+fun! RedBushShowTaps() 
+    if bufwinid('redbush://taps') == -1
+        let winid = win_getid()
+        exe 'belowright split redbush://taps'
+        setlocal buftype=nofile bufhidden=hide noswapfile nobuflisted filetype=clojure
+        setlocal foldmethod=expr foldexpr=RedBushFoldLevel(v:lnum) foldlevel=99
+        let g:redbush_tap_winid = win_getid()
+        call win_gotoid(winid)
+    endif
+    return bufnr('redbush://taps')
+endf
+
+fun! RedBushShowOutput() 
+    if bufwinid('redbush://output') == -1
+        let winid = win_getid()
+        exe 'belowright ' . g:redbush_output_winsize . 'split redbush://output'
+        setlocal buftype=nofile bufhidden=hide noswapfile nobuflisted filetype=clojure
+        setlocal foldmethod=expr foldexpr=RedBushFoldLevel(v:lnum) foldlevel=99
+        let g:redbush_output_winid = win_getid()
+        call win_gotoid(winid)
+    endif
+    return bufnr('redbush://output')
+endf
+
+fun! s:output_toggle() 
+    let winid = bufwinid('redbush://output')
+    if winid != -1
+        exe win_id2win(winid) . 'hide'
+    elseif bufexists('redbush://output')
+        call RedBushShowOutput()
+    endif
+endf
+
+fun! s:toggle_filter() 
+    call s:send_to_plugin('toggle-filter', {})
+endf
+
+fun! s:offer_load_file(ns) 
+    let answer = confirm("Namespace '" . a:ns . "' not found. Load " . expand("%:t") . "?", "&Yes\n&No", 2)
+    if answer == 1
+        call s:eval_file()
+    endif
+endf
+
+fun! RedBushNamespaceNotFound(ns) 
+    call timer_start(0, {-> s:offer_load_file(a:ns)})
+endf
+
 """"""""""""""""""""""
 """" commands
 """"""""""""""""""""""
@@ -220,10 +355,17 @@ command! RedBushStop call s:stop()
 command! RedBushRestart call s:restart()
 command! -range RedBushEvalRange call s:eval_range()
 command! RedBushEvalForm call s:eval_form()
+command! RedBushEvalTopForm call s:eval_top_form()
+command! RedBushEvalFormBefore call s:eval_form_before()
 command! RedBushEvalFile call s:eval_file()
 command! RedBushToggle call s:logbuf_toggle()
 command! RedBushRunTests call s:run_tests()
 command! RedBushEvalFormTime call s:eval_form_time()
+command! RedBushQuickfix call s:quickfix()
+command! RedBushLastException call s:last_exception()
+command! RedBushTaps call RedBushShowTaps()
+command! RedBushToggleOutput call s:output_toggle()
+command! RedBushToggleFilter call s:toggle_filter()
 command! -nargs=1 RedBushConnect call s:start(<q-args>)
 
 """"""""""""""""""""""
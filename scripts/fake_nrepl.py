@@ -0,0 +1,133 @@
+#!/usr/bin/env python3
+"""Fake nREPL server for benchmarking the log buffer of redbush.
+
+Every evaluation prints LINES lines of output, one line per message, before
+returning its value. The time it took to send the output is printed when the
+evaluation is done. The handshake of redbush, an eval without an id, only
+gets a value. Each connection is served in a thread of its own, as redbush
+keeps its handshake connection open. Usage:
+
+    python3 scripts/fake_nrepl.py [--port 7888] [--lines 10000]
+"""
+
+import argparse
+import socketserver
+import time
+import uuid
+
+
+def encode(val):
+    if isinstance(val, int):
+        return b"i%de" % val
+    if isinstance(val, str):
+        val = val.encode()
+    if isinstance(val, bytes):
+        return b"%d:%s" % (len(val), val)
+    if isinstance(val, list):
+        return b"l" + b"".join(encode(v) for v in val) + b"e"
+    if isinstance(val, dict):
+        items = sorted(val.items())
+        return b"d" + b"".join(encode(k) + encode(v) for k, v in items) + b"e"
+    raise TypeError(val)
+
+
+class Reader:
+    def __init__(self, conn):
+        self.conn = conn
+        self.buf = b""
+
+    def byte(self):
+        if not self.buf:
+            self.buf = self.conn.recv(4096)
+            if not self.buf:
+                raise EOFError
+        b, self.buf = self.buf[:1], self.buf[1:]
+        return b
+
+    def until(self, end):
+        s = b""
+        while True:
+            b = self.byte()
+            if b == end:
+                return s
+            s += b
+
+    def read(self, first=None):
+        b = first or self.byte()
+        if b == b"i":
+            return int(self.until(b"e"))
+        if b == b"l":
+            items = []
+            while True:
+                b = self.byte()
+                if b == b"e":
+                    return items
+                items.append(self.read(b))
+        if b == b"d":
+            d = {}
+            while True:
+                b = self.byte()
+                if b == b"e":
+                    return d
+                key = self.read(b)
+                d[key] = self.read()
+        n = int(b + self.until(b":"))
+        s = b""
+        while len(s) < n:
+            s += self.byte()
+        return s.decode()
+
+
+def serve(conn, lines):
+    reader = Reader(conn)
+    while True:
+        msg = reader.read()
+        reply = {"id": msg.get("id", ""), "session": msg.get("session", "")}
+        op = msg.get("op")
+
+        if op == "eval" and "id" not in msg:
+            conn.sendall(encode(dict(reply, value="2", ns="user", status=["done"])))
+        elif op == "clone":
+            conn.sendall(encode(dict(reply, **{"new-session": str(uuid.uuid4()),
+                                               "status": ["done"]})))
+        elif op in ("eval", "load-file") and msg.get("id") != "redbush-tap":
+            started = time.monotonic()
+            for i in range(lines):
+                conn.sendall(encode(dict(reply, out="line %d\n" % i)))
+            conn.sendall(encode(dict(reply, value="nil", ns="user")))
+            conn.sendall(encode(dict(reply, status=["done"])))
+            elapsed = time.monotonic() - started
+            print("%d lines in %.2f s (%.0f lines/s)" % (lines, elapsed, lines / elapsed))
+        else:
+            conn.sendall(encode(dict(reply, status=["done"])))
+
+
+def handler(lines):
+    class Handler(socketserver.BaseRequestHandler):
+        def handle(self):
+            try:
+                serve(self.request, lines)
+            except (EOFError, ConnectionError):
+                print("Client disconnected")
+
+    return Handler
+
+
+class Server(socketserver.ThreadingTCPServer):
+    allow_reuse_address = True
+    daemon_threads = True
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--port", type=int, default=7888)
+    parser.add_argument("--lines", type=int, default=10000)
+    args = parser.parse_args()
+
+    with Server(("127.0.0.1", args.port), handler(args.lines)) as server:
+        print("Fake nREPL listening on port %d" % args.port)
+        server.serve_forever()
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,439 @@
+//! ANSI escape sequences in REPL output.
+//!
+//! Colours and text attributes are set with SGR sequences, e.g. `ESC[31m`
+//! for red and `ESC[0m` for reset. They are turned into highlight groups
+//! named after the style, e.g. `RedBushAnsi_fg1_bold`, which are defined in
+//! NVIM when first used. Other escape sequences (cursor movement, erasing,
+//! titles) are stripped.
+
+use crate::Result;
+use neovim_lib::{Neovim, NeovimApi};
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// Longest incomplete escape sequence held for the next chunk. Longer ones,
+/// e.g. of an OSC sequence missing its terminator, are shown as text.
+const MAX_PENDING: usize = 4096;
+
+/// xterm colours of the 16 basic colour indexes.
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0xcd, 0x00, 0x00),
+    (0x00, 0xcd, 0x00),
+    (0xcd, 0xcd, 0x00),
+    (0x00, 0x00, 0xee),
+    (0xcd, 0x00, 0xcd),
+    (0x00, 0xcd, 0xcd),
+    (0xe5, 0xe5, 0xe5),
+    (0x7f, 0x7f, 0x7f),
+    (0xff, 0x00, 0x00),
+    (0x00, 0xff, 0x00),
+    (0xff, 0xff, 0x00),
+    (0x5c, 0x5c, 0xff),
+    (0xff, 0x00, 0xff),
+    (0x00, 0xff, 0xff),
+    (0xff, 0xff, 0xff),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// index of the 256 colour palette
+    Index(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            Color::Index(i) if i < 16 => PALETTE[i as usize],
+            Color::Index(i) if i < 232 => {
+                let level = |c: u8| if c == 0 { 0 } else { 55 + 40 * c };
+                let i = i - 16;
+                (level(i / 36), level(i / 6 % 6), level(i % 6))
+            }
+            Color::Index(i) => {
+                let gray = 8 + 10 * (i - 232);
+                (gray, gray, gray)
+            }
+            Color::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+
+    fn hex(self) -> String {
+        let (r, g, b) = self.rgb();
+        format!("{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    fn name(self) -> String {
+        match self {
+            Color::Index(i) => i.to_string(),
+            Color::Rgb(..) => self.hex(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Style {
+    fn is_default(&self) -> bool {
+        *self == Style::default()
+    }
+
+    /// Applies the SGR parameters `params`, e.g. `1;31` of `ESC[1;31m`.
+    fn apply(&mut self, params: &str) {
+        let mut codes = params
+            .split(&[';', ':'][..])
+            .map(|p| p.parse::<u16>().unwrap_or(0));
+
+        while let Some(code) = codes.next() {
+            match code {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                7 => self.reverse = true,
+                22 => self.bold = false,
+                23 => self.italic = false,
+                24 => self.underline = false,
+                27 => self.reverse = false,
+                30..=37 => self.fg = Some(Color::Index((code - 30) as u8)),
+                38 => self.fg = extended_color(&mut codes),
+                39 => self.fg = None,
+                40..=47 => self.bg = Some(Color::Index((code - 40) as u8)),
+                48 => self.bg = extended_color(&mut codes),
+                49 => self.bg = None,
+                90..=97 => self.fg = Some(Color::Index((code - 90 + 8) as u8)),
+                100..=107 => self.bg = Some(Color::Index((code - 100 + 8) as u8)),
+                _ => (),
+            }
+        }
+    }
+
+    /// Name of the highlight group of the style.
+    pub fn hl_group(&self) -> String {
+        let mut name = "RedBushAnsi".to_string();
+        if let Some(fg) = self.fg {
+            name.push_str(&format!("_fg{}", fg.name()));
+        }
+        if let Some(bg) = self.bg {
+            name.push_str(&format!("_bg{}", bg.name()));
+        }
+        for (set, attr) in &[
+            (self.bold, "bold"),
+            (self.italic, "italic"),
+            (self.underline, "underline"),
+            (self.reverse, "reverse"),
+        ] {
+            if *set {
+                name.push('_');
+                name.push_str(attr);
+            }
+        }
+
+        name
+    }
+
+    /// Attributes of the highlight group as given to `nvim_set_hl`.
+    fn hl_attrs(&self) -> Vec<(neovim_lib::Value, neovim_lib::Value)> {
+        let mut attrs: Vec<(neovim_lib::Value, neovim_lib::Value)> = vec![];
+        if let Some(fg) = self.fg {
+            attrs.push(("fg".into(), format!("#{}", fg.hex()).into()));
+            if let Color::Index(i) = fg {
+                attrs.push(("ctermfg".into(), (i as i64).into()));
+            }
+        }
+        if let Some(bg) = self.bg {
+            attrs.push(("bg".into(), format!("#{}", bg.hex()).into()));
+            if let Color::Index(i) = bg {
+                attrs.push(("ctermbg".into(), (i as i64).into()));
+            }
+        }
+        for (set, attr) in &[
+            (self.bold, "bold"),
+            (self.italic, "italic"),
+            (self.underline, "underline"),
+            (self.reverse, "reverse"),
+        ] {
+            if *set {
+                attrs.push((attr.to_string().into(), true.into()));
+            }
+        }
+
+        attrs
+    }
+}
+
+/// Colour of `38;5;n` and `38;2;r;g;b` parameters, the codes following 38
+/// or 48 being `codes`.
+fn extended_color(codes: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match codes.next() {
+        Some(5) => codes.next().map(|i| Color::Index(i as u8)),
+        Some(2) => {
+            let r = codes.next()? as u8;
+            let g = codes.next()? as u8;
+            let b = codes.next()? as u8;
+            Some(Color::Rgb(r, g, b))
+        }
+        _ => None,
+    }
+}
+
+/// Parser of the escapes of one output stream. The style carries over from
+/// one chunk of output to the next.
+#[derive(Debug, Default)]
+pub struct Parser {
+    style: Style,
+    /// incomplete escape sequence at the end of the previous chunk
+    pending: String,
+}
+
+impl Parser {
+    /// `text` without escape sequences, and the byte ranges of the styled
+    /// parts of it.
+    pub fn parse(&mut self, text: &str) -> (String, Vec<(Range<usize>, Style)>) {
+        let text = format!("{}{}", std::mem::take(&mut self.pending), text);
+        let mut plain = String::with_capacity(text.len());
+        let mut styles: Vec<(Range<usize>, Style)> = vec![];
+        let mut start = 0;
+
+        let mut rest = text.as_str();
+        while let Some(i) = rest.find('\x1b') {
+            plain.push_str(&rest[..i]);
+            let seq = &rest[i..];
+            let len = match sequence_len(seq) {
+                Some(len) => len,
+                None if seq.len() <= MAX_PENDING => {
+                    self.pending = seq.to_string();
+                    rest = "";
+                    break;
+                }
+                None => {
+                    rest = &seq[1..];
+                    continue;
+                }
+            };
+
+            if seq.starts_with("\x1b[") && seq[..len].ends_with('m') {
+                if !self.style.is_default() && plain.len() > start {
+                    styles.push((start..plain.len(), self.style));
+                }
+                self.style.apply(&seq[2..len - 1]);
+                start = plain.len();
+            }
+            rest = &seq[len..];
+        }
+        plain.push_str(rest);
+
+        if !self.style.is_default() && plain.len() > start {
+            styles.push((start..plain.len(), self.style));
+        }
+
+        (plain, styles)
+    }
+
+    /// Ends the output, showing an incomplete escape sequence held for the
+    /// next chunk as text, like `parse`.
+    pub fn end(&mut self) -> (String, Vec<(Range<usize>, Style)>) {
+        let mut plain = String::new();
+        let mut styles: Vec<(Range<usize>, Style)> = vec![];
+        while !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            let (text, text_styles) = self.parse(&pending[1..]);
+            let offset = plain.len();
+            styles.extend(
+                text_styles
+                    .into_iter()
+                    .map(|(r, style)| (r.start + offset..r.end + offset, style)),
+            );
+            plain.push_str(&text);
+        }
+
+        (plain, styles)
+    }
+}
+
+/// Length of the escape sequence at the start of `s`, `None` if it is
+/// incomplete.
+fn sequence_len(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    match bytes.get(1)? {
+        // CSI: parameters and intermediates up to a final byte
+        b'[' => bytes[2..]
+            .iter()
+            .position(|b| (0x40..=0x7e).contains(b))
+            .map(|i| i + 3),
+        // OSC: up to BEL or ST
+        b']' => {
+            let bel = s.find('\x07').map(|i| i + 1);
+            let st = s.find("\x1b\\").map(|i| i + 2);
+            match (bel, st) {
+                (Some(bel), Some(st)) => Some(bel.min(st)),
+                (bel, st) => bel.or(st),
+            }
+        }
+        // ESC and a single character
+        _ => Some(1 + s[1..].chars().next()?.len_utf8()),
+    }
+}
+
+/// Highlight groups of the styles, defined in NVIM when first used.
+#[derive(Debug, Default)]
+pub struct Highlights {
+    /// false when escapes are only stripped
+    enabled: bool,
+    defined: HashSet<String>,
+}
+
+impl Highlights {
+    pub fn new(enabled: bool) -> Highlights {
+        Highlights {
+            enabled,
+            defined: HashSet::new(),
+        }
+    }
+
+    /// Highlight groups of the ranges of `styles`, defining them if needed.
+    pub fn groups(
+        &mut self,
+        nvim: &mut Neovim,
+        styles: Vec<(Range<usize>, Style)>,
+    ) -> Result<Vec<(Range<usize>, String)>> {
+        if !self.enabled {
+            return Ok(vec![]);
+        }
+
+        let mut groups = vec![];
+        for (range, style) in styles {
+            let name = style.hl_group();
+            if !self.defined.contains(&name) {
+                log::debug!("Defining highlight group: {}", name);
+                nvim.call_function(
+                    "nvim_set_hl",
+                    vec![
+                        0.into(),
+                        name.as_str().into(),
+                        neovim_lib::Value::Map(style.hl_attrs()),
+                    ],
+                )?;
+                self.defined.insert(name.to_string());
+            }
+            groups.push((range, name));
+        }
+
+        Ok(groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn red() -> Style {
+        Style {
+            fg: Some(Color::Index(1)),
+            ..Style::default()
+        }
+    }
+
+    #[test]
+    fn basic_colors_and_resets() {
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.parse("a\x1b[31mred\x1b[0m b \x1b[1;31mbold\x1b[22m red\x1b[m"),
+            (
+                "ared b bold red".to_string(),
+                vec![
+                    (1..4, red()),
+                    (
+                        7..11,
+                        Style {
+                            bold: true,
+                            ..red()
+                        }
+                    ),
+                    (11..15, red()),
+                ]
+            )
+        );
+        assert_eq!(parser.parse("plain"), ("plain".to_string(), vec![]));
+        assert_eq!(
+            Style {
+                bold: true,
+                ..red()
+            }
+            .hl_group(),
+            "RedBushAnsi_fg1_bold"
+        );
+    }
+
+    #[test]
+    fn extended_colors() {
+        let mut parser = Parser::default();
+        let (text, styles) = parser.parse("\x1b[38;5;208ma\x1b[48;2;1;2;3mb\x1b[39;49mc");
+        assert_eq!(text, "abc");
+        assert_eq!(
+            styles,
+            vec![
+                (
+                    0..1,
+                    Style {
+                        fg: Some(Color::Index(208)),
+                        ..Style::default()
+                    }
+                ),
+                (
+                    1..2,
+                    Style {
+                        fg: Some(Color::Index(208)),
+                        bg: Some(Color::Rgb(1, 2, 3)),
+                        ..Style::default()
+                    }
+                ),
+            ]
+        );
+        assert_eq!(styles[1].1.hl_group(), "RedBushAnsi_fg208_bg010203");
+        assert_eq!(Color::Index(208).hex(), "ff8700");
+        assert_eq!(Color::Index(244).hex(), "808080");
+    }
+
+    #[test]
+    fn sequences_split_across_chunks() {
+        let mut parser = Parser::default();
+        assert_eq!(parser.parse("a\x1b[3"), ("a".to_string(), vec![]));
+        assert_eq!(parser.parse("1mb"), ("b".to_string(), vec![(0..1, red())]));
+        assert_eq!(
+            parser.parse("c\x1b"),
+            ("c".to_string(), vec![(0..1, red())])
+        );
+        assert_eq!(parser.parse("[0md"), ("d".to_string(), vec![]));
+    }
+
+    #[test]
+    fn other_sequences_are_stripped() {
+        let mut parser = Parser::default();
+        let (text, styles) = parser.parse("\x1b]0;title\x07a\x1b[2Kb\x1b]8;;url\x1b\\c");
+        assert_eq!(text, "abc");
+        assert!(styles.is_empty());
+    }
+
+    #[test]
+    fn unterminated_sequences_are_shown_as_text() {
+        let mut parser = Parser::default();
+        assert_eq!(parser.parse("a\x1b]0;title"), ("a".to_string(), vec![]));
+        assert_eq!(parser.end(), ("]0;title".to_string(), vec![]));
+        assert_eq!(parser.end(), ("".to_string(), vec![]));
+
+        let long = format!("\x1b]{}", "x".repeat(MAX_PENDING));
+        let (text, _) = parser.parse(&format!("{}\x1b[31mb", long));
+        assert_eq!(text, format!("{}b", &long[1..]));
+        assert_eq!(parser.end(), ("".to_string(), vec![]));
+    }
+}
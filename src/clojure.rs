@@ -0,0 +1,537 @@
+//! Tolerant reader for Clojure source text.
+//!
+//! This is not a full Clojure reader: it only finds the extent of every form
+//! (as byte offsets into the source) so that the plugin can reason about
+//! buffer contents, e.g. find the `ns` declaration. Unbalanced input is
+//! accepted; unterminated forms simply end at the end of the source.
+
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    List,
+    Vector,
+    Map,
+    Set,
+    Fn,
+    String,
+    Regex,
+    Char,
+    Token,
+    /// `^meta form`, children are the metadata and the form
+    Meta,
+    /// `#_ form`
+    Discard,
+    /// quote, syntax-quote, unquote, deref, var quote, reader conditionals,
+    /// namespaced maps and tagged literals
+    Macro,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Form {
+    pub kind: Kind,
+    pub start: usize,
+    pub end: usize,
+    pub children: Vec<Form>,
+}
+
+impl Form {
+    fn new(kind: Kind, start: usize, end: usize) -> Form {
+        Form {
+            kind,
+            start,
+            end,
+            children: vec![],
+        }
+    }
+
+    pub fn text<'a>(&self, src: &'a str) -> &'a str {
+        &src[self.start..self.end]
+    }
+
+    /// The form with metadata stripped off, i.e. `foo` for `^:private foo`.
+    pub fn unwrap_meta(&self) -> &Form {
+        match self.kind {
+            Kind::Meta => match self.children.last() {
+                Some(form) => form.unwrap_meta(),
+                None => self,
+            },
+            _ => self,
+        }
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+/// Whether `c` separates forms, commas being whitespace in Clojure. Bytes of
+/// UTF-8 text can be checked as `char::from(b)`, as only ASCII characters
+/// match.
+pub fn is_whitespace(c: char) -> bool {
+    c.is_ascii_whitespace() || c == ','
+}
+
+/// Whether `c` ends a symbol, keyword, number or character literal.
+pub fn is_terminator(c: char) -> bool {
+    is_whitespace(c) || "\";@^`~()[]{}\\".contains(c)
+}
+
+fn is_closer(b: u8) -> bool {
+    b == b')' || b == b']' || b == b'}'
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.src.get(self.pos + offset).copied()
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(b) = self.peek() {
+            self.pos += 1;
+            if b == b'\n' {
+                break;
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b) = self.peek() {
+            if is_whitespace(b.into()) {
+                self.pos += 1;
+            } else if b == b';' || (b == b'#' && self.peek_at(1) == Some(b'!')) {
+                self.skip_line();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn skip_token(&mut self) {
+        while let Some(b) = self.peek() {
+            if is_terminator(b.into()) {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn skip_utf8_char(&mut self) {
+        self.pos += 1;
+        while let Some(b) = self.peek() {
+            if b & 0xC0 != 0x80 {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// Reads a string body, `self.pos` being just after the opening quote.
+    fn skip_string(&mut self) {
+        while let Some(b) = self.peek() {
+            self.pos += 1;
+            match b {
+                b'\\' => self.pos = (self.pos + 1).min(self.src.len()),
+                b'"' => break,
+                _ => (),
+            }
+        }
+    }
+
+    /// Reads forms until a closing delimiter or the end of the source.
+    fn read_children(&mut self) -> Vec<Form> {
+        let mut children = vec![];
+        while let Some(form) = self.read() {
+            children.push(form);
+        }
+        children
+    }
+
+    fn read_coll(&mut self, kind: Kind, start: usize, open_len: usize) -> Form {
+        self.pos += open_len;
+        let children = self.read_children();
+        if self.peek().is_some_and(is_closer) {
+            self.pos += 1;
+        }
+        Form {
+            kind,
+            start,
+            end: self.pos,
+            children,
+        }
+    }
+
+    fn read_prefixed(&mut self, kind: Kind, start: usize, prefix_len: usize, cnt: usize) -> Form {
+        self.pos += prefix_len;
+        let mut form = Form::new(kind, start, self.pos);
+        for _ in 0..cnt {
+            match self.read() {
+                Some(child) => {
+                    form.end = child.end;
+                    form.children.push(child);
+                }
+                None => break,
+            }
+        }
+        form
+    }
+
+    fn read_dispatch(&mut self, start: usize) -> Form {
+        match self.peek_at(1) {
+            Some(b'{') => self.read_coll(Kind::Set, start, 2),
+            Some(b'(') => self.read_coll(Kind::Fn, start, 2),
+            Some(b'"') => {
+                self.pos += 2;
+                self.skip_string();
+                Form::new(Kind::Regex, start, self.pos)
+            }
+            Some(b'_') => self.read_prefixed(Kind::Discard, start, 2, 1),
+            Some(b'\'') | Some(b'=') => self.read_prefixed(Kind::Macro, start, 2, 1),
+            Some(b'?') => {
+                let len = if self.peek_at(2) == Some(b'@') { 3 } else { 2 };
+                self.read_prefixed(Kind::Macro, start, len, 1)
+            }
+            Some(b'#') => {
+                self.pos += 2;
+                self.skip_token();
+                Form::new(Kind::Token, start, self.pos)
+            }
+            Some(b':') => {
+                // namespaced map, `#:ns{...}` or `#::{...}`
+                self.pos += 1;
+                self.skip_token();
+                self.read_prefixed(Kind::Macro, start, 0, 1)
+            }
+            _ => {
+                // tagged literal, the tag being part of the prefix
+                self.pos += 1;
+                self.skip_token();
+                self.read_prefixed(Kind::Macro, start, 0, 1)
+            }
+        }
+    }
+
+    /// Reads the next form, skipping whitespace and comments. Returns `None`
+    /// at the end of the source or at a closing delimiter, which is left
+    /// unconsumed.
+    fn read(&mut self) -> Option<Form> {
+        self.skip_whitespace();
+        let start = self.pos;
+
+        let form = match self.peek()? {
+            b')' | b']' | b'}' => return None,
+            b'(' => self.read_coll(Kind::List, start, 1),
+            b'[' => self.read_coll(Kind::Vector, start, 1),
+            b'{' => self.read_coll(Kind::Map, start, 1),
+            b'"' => {
+                self.pos += 1;
+                self.skip_string();
+                Form::new(Kind::String, start, self.pos)
+            }
+            b'\\' => {
+                self.pos += 1;
+                if self.peek().is_some() {
+                    self.skip_utf8_char();
+                    self.skip_token();
+                }
+                Form::new(Kind::Char, start, self.pos)
+            }
+            b'^' => self.read_prefixed(Kind::Meta, start, 1, 2),
+            b'\'' | b'`' | b'@' => self.read_prefixed(Kind::Macro, start, 1, 1),
+            b'~' => {
+                let len = if self.peek_at(1) == Some(b'@') { 2 } else { 1 };
+                self.read_prefixed(Kind::Macro, start, len, 1)
+            }
+            b'#' => self.read_dispatch(start),
+            _ => {
+                self.skip_utf8_char();
+                self.skip_token();
+                Form::new(Kind::Token, start, self.pos)
+            }
+        };
+
+        Some(form)
+    }
+}
+
+/// Reads all top-level forms of `src`. Stray closing delimiters are skipped.
+pub fn read_forms(src: &str) -> Vec<Form> {
+    let mut parser = Parser {
+        src: src.as_bytes(),
+        pos: 0,
+    };
+    let mut forms = vec![];
+
+    loop {
+        forms.append(&mut parser.read_children());
+        if parser.peek().is_none() {
+            break;
+        }
+        parser.pos += 1;
+    }
+
+    forms
+}
+
+/// Name of the namespace declared by the first `ns` form in `src`.
+pub fn ns_name(src: &str) -> Option<String> {
+    read_forms(src)
+        .iter()
+        .map(Form::unwrap_meta)
+        .filter(|form| form.kind == Kind::List)
+        .find_map(|form| {
+            let mut children = form.children.iter().map(Form::unwrap_meta);
+            match children.next() {
+                Some(sym) if sym.kind == Kind::Token => match sym.text(src) {
+                    "ns" | "clojure.core/ns" => children
+                        .next()
+                        .filter(|name| name.kind == Kind::Token)
+                        .map(|name| name.text(src).to_string()),
+                    _ => None,
+                },
+                _ => None,
+            }
+        })
+}
+
+fn is_coll(form: &Form) -> bool {
+    match form.kind {
+        Kind::List | Kind::Vector | Kind::Map | Kind::Set | Kind::Fn => true,
+        Kind::Macro | Kind::Meta => form.children.last().is_some_and(is_coll),
+        _ => false,
+    }
+}
+
+fn contains(form: &Form, offset: usize) -> bool {
+    form.start <= offset && offset < form.end
+}
+
+fn is_comment(src: &str, form: &Form) -> bool {
+    form.children
+        .first()
+        .is_some_and(|sym| sym.text(src) == "comment")
+}
+
+fn innermost_coll(forms: &[Form], offset: usize) -> Option<&Form> {
+    let form = forms.iter().find(|f| contains(f, offset))?;
+
+    match innermost_coll(&form.children, offset) {
+        // a reader macro wrapping the collection belongs to it, e.g. `'(1 2)`
+        Some(inner)
+            if (form.kind == Kind::Macro || form.kind == Kind::Meta)
+                && is_coll(form)
+                && form.children.last().is_some_and(|l| std::ptr::eq(l, inner)) =>
+        {
+            Some(form)
+        }
+        Some(inner) => Some(inner),
+        None if is_coll(form) => Some(form),
+        None => None,
+    }
+}
+
+fn last_before(forms: &[Form], point: usize) -> Option<&Form> {
+    match forms.iter().find(|f| f.start < point && point < f.end) {
+        Some(form) if form.children.is_empty() => Some(form),
+        Some(form) => last_before(&form.children, point),
+        None => forms.iter().rev().find(|f| f.end <= point),
+    }
+}
+
+/// Byte range of the innermost collection containing byte `offset`, or of
+/// the top-level atom at `offset`.
+pub fn form_at(src: &str, offset: usize) -> Option<Range<usize>> {
+    let forms = read_forms(src);
+
+    innermost_coll(&forms, offset)
+        .or_else(|| forms.iter().find(|f| contains(f, offset)))
+        .map(|form| form.start..form.end)
+}
+
+/// Byte range of the top-level form containing byte `offset`. Forms inside
+/// `(comment ...)` and after `#_` count as top-level forms.
+pub fn top_level_form_at(src: &str, offset: usize) -> Option<Range<usize>> {
+    let forms = read_forms(src);
+    let mut form = forms.iter().find(|f| contains(f, offset))?;
+
+    loop {
+        let nested = match form.kind {
+            Kind::Discard => &form.children[..],
+            Kind::List if is_comment(src, form) => &form.children[1..],
+            _ => break,
+        };
+        match nested.iter().find(|f| contains(f, offset)) {
+            Some(child) => form = child,
+            None => break,
+        }
+    }
+
+    Some(form.start..form.end)
+}
+
+/// Byte range of the form ending at or before the character at byte `offset`,
+/// on the innermost level containing it.
+pub fn form_before(src: &str, offset: usize) -> Option<Range<usize>> {
+    let forms = read_forms(src);
+
+    last_before(&forms, offset + 1).map(|form| form.start..form.end)
+}
+
+/// Byte offset of 1-based `line` and byte `column`, clamped to the line.
+pub fn offset(src: &str, line: usize, column: usize) -> usize {
+    let start: usize = src
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    let len = src[start..].find('\n').unwrap_or(src.len() - start);
+
+    start + column.saturating_sub(1).min(len)
+}
+
+/// 1-based line and character column of byte `offset`.
+pub fn position(src: &str, offset: usize) -> (usize, usize) {
+    let before = &src[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(src: &str) -> Vec<&str> {
+        read_forms(src).iter().map(|f| f.text(src)).collect()
+    }
+
+    fn at(src: &str, offset: usize) -> Option<&str> {
+        form_at(src, offset).map(|r| &src[r])
+    }
+
+    #[test]
+    fn read_forms_strings_and_chars() {
+        assert_eq!(texts(r#"(a "(b" ")") c"#), vec![r#"(a "(b" ")")"#, "c"]);
+        assert_eq!(texts(r#""a \" (" b"#), vec![r#""a \" (""#, "b"]);
+        assert_eq!(texts(r"(f \( \) \space) x"), vec![r"(f \( \) \space)", "x"]);
+        assert_eq!(texts(r#"#"\(" y"#), vec![r#"#"\(""#, "y"]);
+    }
+
+    #[test]
+    fn read_forms_comments_and_discard() {
+        assert_eq!(texts("; (a\n(b) ;; )\nc"), vec!["(b)", "c"]);
+        assert_eq!(texts("#! shebang (\n(a)"), vec!["(a)"]);
+
+        let forms = read_forms("#_(a) b");
+        assert_eq!(forms.len(), 2);
+        assert_eq!(forms[0].kind, Kind::Discard);
+        assert_eq!(forms[0].children[0].kind, Kind::List);
+        assert_eq!(forms[1].end, 7);
+    }
+
+    #[test]
+    fn read_forms_reader_macros() {
+        let src = "#?(:clj 1 :cljs 2) #?@(:clj [a]) #inst \"2020\" ^:x y";
+        assert_eq!(
+            texts(src),
+            vec![
+                "#?(:clj 1 :cljs 2)",
+                "#?@(:clj [a])",
+                "#inst \"2020\"",
+                "^:x y"
+            ]
+        );
+        let forms = read_forms(src);
+        assert_eq!(forms[0].kind, Kind::Macro);
+        assert_eq!(forms[0].children[0].kind, Kind::List);
+        assert_eq!(forms[3].unwrap_meta().text(src), "y");
+    }
+
+    #[test]
+    fn read_forms_unbalanced() {
+        assert_eq!(texts(") (a [b) c"), vec!["(a [b) c"]);
+        assert_eq!(texts("(a (b"), vec!["(a (b"]);
+    }
+
+    #[test]
+    fn ns_name_of_buffer() {
+        assert_eq!(
+            ns_name("; x\n(ns ^:no-doc foo.bar\n  (:require baz))").as_deref(),
+            Some("foo.bar")
+        );
+        assert_eq!(
+            ns_name("(ns ^{:doc \"x\"} foo.bar)").as_deref(),
+            Some("foo.bar")
+        );
+        assert_eq!(ns_name("(comment)\n(ns a.b)").as_deref(), Some("a.b"));
+    }
+
+    #[test]
+    fn form_at_edges() {
+        let src = "(a (b c) d)";
+        assert_eq!(at(src, 0), Some("(a (b c) d)"));
+        assert_eq!(at(src, 3), Some("(b c)"));
+        assert_eq!(at(src, 5), Some("(b c)"));
+        assert_eq!(at(src, 7), Some("(b c)"));
+        assert_eq!(at(src, 8), Some("(a (b c) d)"));
+        assert_eq!(at(src, 10), Some("(a (b c) d)"));
+        assert_eq!(at(src, 11), None);
+        assert_eq!(at("foo bar", 5), Some("bar"));
+        assert_eq!(at("foo bar", 3), None);
+    }
+
+    #[test]
+    fn form_at_strings_chars_and_macros() {
+        let src = r#"(a "(b)" \( '(c))"#;
+        assert_eq!(at(src, 4), Some(src));
+        assert_eq!(at(src, 10), Some(src));
+        assert_eq!(at(src, 13), Some("'(c)"));
+        assert_eq!(at(src, 14), Some("'(c)"));
+        assert_eq!(at("#?(:clj (x))", 9), Some("(x)"));
+        assert_eq!(at("#?(:clj (x))", 3), Some("#?(:clj (x))"));
+    }
+
+    #[test]
+    fn top_level_form_at_comment_and_discard() {
+        let src = "(comment\n  (a 1)\n  (b (c)))\n#_(d) (e)";
+        let top = |offset| top_level_form_at(src, offset).map(|r| &src[r]);
+        assert_eq!(top(0), Some("(comment\n  (a 1)\n  (b (c)))"));
+        assert_eq!(top(13), Some("(a 1)"));
+        assert_eq!(top(24), Some("(b (c))"));
+        assert_eq!(top(30), Some("(d)"));
+        assert_eq!(top(28), Some("#_(d)"));
+        assert_eq!(top(34), Some("(e)"));
+        assert_eq!(top(33), None);
+    }
+
+    #[test]
+    fn form_before_cursor() {
+        let src = "(a (b c) d)";
+        let before = |offset| form_before(src, offset).map(|r| &src[r]);
+        assert_eq!(before(7), Some("(b c)"));
+        assert_eq!(before(8), Some("(b c)"));
+        assert_eq!(before(6), Some("c"));
+        assert_eq!(before(9), Some("d"));
+        assert_eq!(before(10), Some(src));
+        assert_eq!(before(0), None);
+        assert_eq!(form_before(r#"(f "a)")"#, 6).map(|r| r.start), Some(3));
+    }
+
+    #[test]
+    fn offset_and_position() {
+        let src = "(a)\n(bé c)\n";
+        assert_eq!(offset(src, 1, 1), 0);
+        assert_eq!(offset(src, 2, 5), 8);
+        assert_eq!(offset(src, 2, 99), 11);
+        assert_eq!(position(src, 8), (2, 4));
+        assert_eq!(position(src, 0), (1, 1));
+    }
+}
@@ -0,0 +1,280 @@
+//! Compiler errors and warnings as Neovim diagnostics.
+//!
+//! Compile exceptions carry their location in `:clojure.error/*` ex-data,
+//! reflection and boxed math warnings are printed to `*err*` as e.g.
+//! `Reflection warning, foo.clj:12:3 - call to method bar can't be resolved`.
+//! Locations name the file either by its full path (evaluated forms) or by
+//! its classpath-relative path (loaded files), and are matched to buffers by
+//! their names.
+
+use crate::exception::ExceptionInfo;
+use crate::Result;
+use neovim_lib::{Neovim, NeovimApi};
+use std::collections::HashMap;
+use std::ops::Range;
+
+const WARNINGS: [&str; 3] = [
+    "Reflection warning, ",
+    "Boxed math warning, ",
+    "Performance warning, ",
+];
+
+/// Phases of `clojure.main/ex-triage` that happen before evaluation.
+const COMPILE_PHASES: [&str; 5] = [
+    "read-source",
+    "macro-syntax-check",
+    "macroexpansion",
+    "compile-syntax-check",
+    "compilation",
+];
+
+const SET_DIAGNOSTICS: &str = r#"
+local bufnr, items = ...
+local ns = vim.api.nvim_create_namespace("redbush")
+local diagnostics = {}
+for _, item in ipairs(items) do
+    table.insert(diagnostics, {
+        lnum = item[1] - 1,
+        col = item[2] - 1,
+        severity = item[3],
+        message = item[4],
+        source = "redbush",
+    })
+end
+vim.diagnostic.set(ns, bufnr, diagnostics)
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: String,
+    /// 1-based line and column
+    pub line: i64,
+    pub column: i64,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Error for a syntax error or compile exception with a known location.
+    pub fn from_exception(info: &ExceptionInfo) -> Option<Diagnostic> {
+        if !COMPILE_PHASES.contains(&info.phase.as_str()) {
+            return None;
+        }
+
+        Some(Diagnostic {
+            file: info.source.as_ref()?.to_string(),
+            line: info.line?,
+            column: info.column.unwrap_or(1),
+            severity: Severity::Error,
+            message: info.cause.to_string(),
+        })
+    }
+
+    /// Warnings printed by the compiler in `err` output.
+    pub fn parse_warnings(err: &str) -> Vec<Diagnostic> {
+        err.lines()
+            .filter_map(|line| {
+                let prefix = WARNINGS.iter().find(|p| line.starts_with(*p))?;
+                let (location, message) = line[prefix.len()..].split_once(" - ")?;
+                let mut parts = location.rsplitn(3, ':');
+                let column = parts.next()?.parse().ok()?;
+                let line = parts.next()?.parse().ok()?;
+
+                Some(Diagnostic {
+                    file: parts.next()?.to_string(),
+                    line,
+                    column,
+                    severity: Severity::Warning,
+                    message: format!("{}: {}", prefix.trim_end_matches(", "), message),
+                })
+            })
+            .collect()
+    }
+
+    fn to_item(&self) -> neovim_lib::Value {
+        let severity = match self.severity {
+            Severity::Error => 1,
+            Severity::Warning => 2,
+        };
+
+        neovim_lib::Value::Array(vec![
+            self.line.into(),
+            self.column.into(),
+            severity.into(),
+            self.message.as_str().into(),
+        ])
+    }
+}
+
+/// Number of the buffer of `file`, given as a full or a classpath-relative path.
+fn find_buffer(nvim: &mut Neovim, file: &str) -> Result<Option<i64>> {
+    if file.is_empty() {
+        return Ok(None);
+    }
+
+    let suffix = format!("/{}", file.trim_start_matches('/'));
+    for buf in nvim.list_bufs()? {
+        let name = buf.get_name(nvim).unwrap_or_default();
+        if name == file || name.ends_with(&suffix) {
+            return Ok(Some(buf.get_number(nvim)?));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Diagnostics shown in NVIM, by buffer number.
+#[derive(Default)]
+pub struct Diagnostics {
+    buffers: HashMap<i64, Vec<Diagnostic>>,
+}
+
+impl Diagnostics {
+    fn publish(&self, nvim: &mut Neovim, bufno: i64) -> Result<()> {
+        let items: Vec<neovim_lib::Value> = self
+            .buffers
+            .get(&bufno)
+            .map(|diags| diags.iter().map(Diagnostic::to_item).collect())
+            .unwrap_or_default();
+
+        nvim.execute_lua(SET_DIAGNOSTICS, vec![bufno.into(), items.into()])?;
+
+        Ok(())
+    }
+
+    pub fn add(&mut self, nvim: &mut Neovim, diagnostic: Diagnostic) -> Result<()> {
+        log::debug!("Adding diagnostic: {:?}", &diagnostic);
+
+        if let Some(bufno) = find_buffer(nvim, &diagnostic.file)? {
+            let diags = self.buffers.entry(bufno).or_default();
+            if !diags.contains(&diagnostic) {
+                diags.push(diagnostic);
+            }
+            self.publish(nvim, bufno)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the diagnostics of `file` on `lines`, or all of them.
+    pub fn clear(
+        &mut self,
+        nvim: &mut Neovim,
+        file: &str,
+        lines: Option<Range<i64>>,
+    ) -> Result<()> {
+        if let Some(bufno) = find_buffer(nvim, file)? {
+            if let Some(diags) = self.buffers.get_mut(&bufno) {
+                let cnt = diags.len();
+                diags.retain(|d| lines.as_ref().is_some_and(|lines| !lines.contains(&d.line)));
+                if diags.len() != cnt {
+                    self.publish(nvim, bufno)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warning(file: &str, line: i64, column: i64, message: &str) -> Diagnostic {
+        Diagnostic {
+            file: file.to_string(),
+            line,
+            column,
+            severity: Severity::Warning,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn warnings() {
+        let err = "Reflection warning, app/core.clj:12:3 - call to method bar can't be resolved (target class is unknown).\n\
+                   Boxed math warning, app/core.clj:20:5 - call: public static java.lang.Number clojure.lang.Numbers.unchecked_add(java.lang.Object,long).\n\
+                   Performance warning, app/core.clj:31:1 - hash collision of some case test constants; if selected, those entries will be tested sequentially.\n\
+                   Some other output, app/core.clj:1:1 - not a warning\n";
+
+        assert_eq!(
+            Diagnostic::parse_warnings(err),
+            vec![
+                warning(
+                    "app/core.clj",
+                    12,
+                    3,
+                    "Reflection warning: call to method bar can't be resolved (target class is unknown)."
+                ),
+                warning(
+                    "app/core.clj",
+                    20,
+                    5,
+                    "Boxed math warning: call: public static java.lang.Number clojure.lang.Numbers.unchecked_add(java.lang.Object,long)."
+                ),
+                warning(
+                    "app/core.clj",
+                    31,
+                    1,
+                    "Performance warning: hash collision of some case test constants; if selected, those entries will be tested sequentially."
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn warning_in_path_with_colon() {
+        assert_eq!(
+            Diagnostic::parse_warnings(
+                "Reflection warning, C:\\work\\app\\core.clj:7:9 - reference to field x can't be resolved."
+            ),
+            vec![warning(
+                "C:\\work\\app\\core.clj",
+                7,
+                9,
+                "Reflection warning: reference to field x can't be resolved."
+            )]
+        );
+        assert!(Diagnostic::parse_warnings("Reflection warning, core.clj:x:9 - oops").is_empty());
+    }
+
+    #[test]
+    fn errors_of_compile_phases() {
+        let info = |phase: &str| ExceptionInfo {
+            phase: phase.to_string(),
+            source: Some("app/core.clj".to_string()),
+            line: Some(4),
+            column: None,
+            cause: "Unable to resolve symbol: x in this context".to_string(),
+            ..ExceptionInfo::from_class("clojure.lang.Compiler$CompilerException")
+        };
+
+        assert_eq!(
+            Diagnostic::from_exception(&info("compile-syntax-check")),
+            Some(Diagnostic {
+                file: "app/core.clj".to_string(),
+                line: 4,
+                column: 1,
+                severity: Severity::Error,
+                message: "Unable to resolve symbol: x in this context".to_string(),
+            })
+        );
+        for phase in &["execution", "read-eval-result", "print-eval-result"] {
+            assert_eq!(Diagnostic::from_exception(&info(phase)), None);
+        }
+        assert_eq!(
+            Diagnostic::from_exception(&ExceptionInfo {
+                line: None,
+                ..info("read-source")
+            }),
+            None
+        );
+    }
+}
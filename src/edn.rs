@@ -0,0 +1,759 @@
+//! EDN reader for REPL payloads.
+//!
+//! Reads everything a Clojure REPL prints readably plus the common
+//! unreadable bits: tagged literals (`#inst`, `#uuid`, `#object[...]`,
+//! `#error {...}`) keep their tag, namespaced maps get qualified keys,
+//! ratios and big numbers keep their literal text, and regexes and var
+//! quotes are kept as such. Metadata is read and dropped.
+
+use crate::clojure::{is_terminator, is_whitespace};
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    String(String),
+    Char(char),
+    Symbol(String),
+    /// keyword name without the leading colon
+    Keyword(String),
+    Integer(i64),
+    Float(f64),
+    /// arbitrary precision integer, literal text e.g. `123N`
+    BigInt(String),
+    /// arbitrary precision decimal, literal text e.g. `1.5M`
+    BigDec(String),
+    /// literal text e.g. `1/3`
+    Ratio(String),
+    Regex(String),
+    /// `#'ns/name`
+    Var(String),
+    List(Vec<Value>),
+    Vector(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    Tagged(String, Box<Value>),
+}
+
+impl Value {
+    /// Value of keyword `key` in a map, `#error` maps included.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| matches!(k, Value::Keyword(k) if k == key))
+                .map(|(_, v)| v),
+            Value::Tagged(_, value) => value.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Elements of a list, vector or set.
+    pub fn as_slice(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(v) | Value::Vector(v) | Value::Set(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Name of a symbol or keyword.
+    pub fn as_name(&self) -> Option<&str> {
+        match self {
+            Value::Symbol(s) | Value::Keyword(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+fn write_seq(f: &mut fmt::Formatter<'_>, open: &str, vals: &[Value], close: &str) -> fmt::Result {
+    write!(f, "{}", open)?;
+    for (i, val) in vals.iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", val)?;
+    }
+    write!(f, "{}", close)
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Value::Char(c) => match c {
+                '\n' => write!(f, "\\newline"),
+                ' ' => write!(f, "\\space"),
+                '\t' => write!(f, "\\tab"),
+                '\r' => write!(f, "\\return"),
+                _ => write!(f, "\\{}", c),
+            },
+            Value::Symbol(s) => write!(f, "{}", s),
+            Value::Keyword(s) => write!(f, ":{}", s),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(x) if x.is_nan() => write!(f, "##NaN"),
+            Value::Float(x) if x.is_infinite() => {
+                write!(f, "{}", if *x > 0.0 { "##Inf" } else { "##-Inf" })
+            }
+            Value::Float(x) if x.fract() == 0.0 && x.abs() < 1e16 => write!(f, "{:.1}", x),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::BigInt(s) | Value::BigDec(s) | Value::Ratio(s) => write!(f, "{}", s),
+            Value::Regex(s) => write!(f, "#\"{}\"", s),
+            Value::Var(s) => write!(f, "#'{}", s),
+            Value::List(v) => write_seq(f, "(", v, ")"),
+            Value::Vector(v) => write_seq(f, "[", v, "]"),
+            Value::Set(v) => write_seq(f, "#{", v, "}"),
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} {}", k, v)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Tagged(tag, value) => write!(f, "#{} {}", tag, value),
+        }
+    }
+}
+
+impl Value {
+    /// Printed like `Display` does, with collections that do not fit in
+    /// `width` columns broken over several lines.
+    pub fn pretty(&self, width: usize) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0, width);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, indent: usize, width: usize) {
+        let flat = self.to_string();
+        if indent + flat.chars().count() <= width {
+            out.push_str(&flat);
+            return;
+        }
+
+        let newline = |out: &mut String, indent: usize| {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent));
+        };
+
+        match self {
+            Value::List(vals) | Value::Vector(vals) | Value::Set(vals) => {
+                let (open, close) = match self {
+                    Value::List(_) => ("(", ")"),
+                    Value::Vector(_) => ("[", "]"),
+                    _ => ("#{", "}"),
+                };
+                out.push_str(open);
+                for (i, val) in vals.iter().enumerate() {
+                    if i > 0 {
+                        newline(out, indent + open.len());
+                    }
+                    val.write_pretty(out, indent + open.len(), width);
+                }
+                out.push_str(close);
+            }
+            Value::Map(entries) => {
+                out.push('{');
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                        newline(out, indent + 1);
+                    }
+                    let key = k.to_string();
+                    out.push_str(&key);
+                    out.push(' ');
+                    v.write_pretty(out, indent + key.chars().count() + 2, width);
+                }
+                out.push('}');
+            }
+            Value::Tagged(tag, value) => {
+                let prefix = format!("#{} ", tag);
+                out.push_str(&prefix);
+                value.write_pretty(out, indent + prefix.len(), width);
+            }
+            _ => out.push_str(&flat),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub pos: usize,
+    pub message: String,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EDN error at {}: {}", self.pos, self.message)
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+pub struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    pub fn new(s: &str) -> Parser {
+        Parser {
+            chars: s.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    /// Reads the next value, or `None` when only whitespace and comments are left.
+    pub fn read(&mut self) -> Option<Result<Value>> {
+        self.skip_whitespace();
+        self.peek()?;
+        Some(self.read_value())
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T> {
+        Err(Error {
+            pos: self.pos,
+            message: message.to_string(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if is_whitespace(c) {
+                self.pos += 1;
+            } else if c == ';' {
+                while let Some(c) = self.next() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn read_token(&mut self) -> String {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if is_terminator(c) {
+                break;
+            }
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn read_value(&mut self) -> Result<Value> {
+        self.skip_whitespace();
+
+        match self.peek() {
+            None => self.error("unexpected end of input"),
+            Some(c) => match c {
+                '(' => Ok(Value::List(self.read_seq(')')?)),
+                '[' => Ok(Value::Vector(self.read_seq(']')?)),
+                '{' => self.read_map(None),
+                '"' => {
+                    self.pos += 1;
+                    Ok(Value::String(self.read_string()?))
+                }
+                '\\' => self.read_char(),
+                '#' => self.read_dispatch(),
+                '^' => {
+                    self.pos += 1;
+                    self.read_value()?;
+                    self.read_value()
+                }
+                '\'' => self.read_wrapped(1, "quote"),
+                '@' => self.read_wrapped(1, "clojure.core/deref"),
+                '`' => self.read_wrapped(1, "syntax-quote"),
+                '~' if self.chars.get(self.pos + 1) == Some(&'@') => {
+                    self.read_wrapped(2, "clojure.core/unquote-splicing")
+                }
+                '~' => self.read_wrapped(1, "clojure.core/unquote"),
+                ')' | ']' | '}' => self.error(&format!("unmatched delimiter '{}'", c)),
+                _ => {
+                    let token = self.read_token();
+                    self.parse_token(&token)
+                }
+            },
+        }
+    }
+
+    fn read_wrapped(&mut self, prefix_len: usize, sym: &str) -> Result<Value> {
+        self.pos += prefix_len;
+        let value = self.read_value()?;
+        Ok(Value::List(vec![Value::Symbol(sym.to_string()), value]))
+    }
+
+    fn read_seq(&mut self, close: char) -> Result<Vec<Value>> {
+        self.pos += 1;
+        let mut vals = vec![];
+
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => return self.error(&format!("missing '{}'", close)),
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    return Ok(vals);
+                }
+                Some('#') if self.chars.get(self.pos + 1) == Some(&'_') => {
+                    self.pos += 2;
+                    self.read_value()?;
+                }
+                Some(_) => vals.push(self.read_value()?),
+            }
+        }
+    }
+
+    /// Reads a map, qualifying its unqualified keyword keys with `ns`.
+    fn read_map(&mut self, ns: Option<&str>) -> Result<Value> {
+        let vals = self.read_seq('}')?;
+        if vals.len() % 2 != 0 {
+            return self.error("map literal must contain an even number of forms");
+        }
+
+        let mut entries = vec![];
+        let mut iter = vals.into_iter();
+        while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
+            let k = match (ns, k) {
+                (Some(ns), Value::Keyword(name)) if !name.contains('/') => {
+                    Value::Keyword(format!("{}/{}", ns, name))
+                }
+                (Some(_), Value::Keyword(name)) if name.starts_with("_/") => {
+                    Value::Keyword(name[2..].to_string())
+                }
+                (_, k) => k,
+            };
+            entries.push((k, v));
+        }
+
+        Ok(Value::Map(entries))
+    }
+
+    /// Reads a string body, the opening quote already consumed.
+    fn read_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+
+        loop {
+            match self.next() {
+                None => return self.error("unterminated string"),
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = self.chars.iter().skip(self.pos).take(4).collect();
+                        self.pos += 4;
+                        match u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(std::char::from_u32)
+                        {
+                            Some(c) => s.push(c),
+                            None => return self.error(&format!("invalid unicode escape {}", hex)),
+                        }
+                    }
+                    Some(c) if c.is_digit(8) => {
+                        let mut oct = c.to_string();
+                        while oct.len() < 3 && self.peek().is_some_and(|c| c.is_digit(8)) {
+                            oct.push(self.chars[self.pos]);
+                            self.pos += 1;
+                        }
+                        match u32::from_str_radix(&oct, 8)
+                            .ok()
+                            .and_then(std::char::from_u32)
+                        {
+                            Some(c) => s.push(c),
+                            None => return self.error(&format!("invalid octal escape {}", oct)),
+                        }
+                    }
+                    Some(c) => s.push(c),
+                    None => return self.error("unterminated string"),
+                },
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn read_char(&mut self) -> Result<Value> {
+        self.pos += 1;
+        let first = match self.next() {
+            Some(c) => c,
+            None => return self.error("unexpected end of input in character"),
+        };
+        let rest = self.read_token();
+        if rest.is_empty() {
+            return Ok(Value::Char(first));
+        }
+
+        let name = format!("{}{}", first, rest);
+        let c = match name.as_str() {
+            "newline" => Some('\n'),
+            "space" => Some(' '),
+            "tab" => Some('\t'),
+            "return" => Some('\r'),
+            "backspace" => Some('\u{8}'),
+            "formfeed" => Some('\u{c}'),
+            _ if first == 'u' && rest.len() == 4 => u32::from_str_radix(&rest, 16)
+                .ok()
+                .and_then(std::char::from_u32),
+            _ if first == 'o' => u32::from_str_radix(&rest, 8)
+                .ok()
+                .and_then(std::char::from_u32),
+            _ => None,
+        };
+
+        match c {
+            Some(c) => Ok(Value::Char(c)),
+            None => self.error(&format!("unsupported character \\{}", name)),
+        }
+    }
+
+    fn read_dispatch(&mut self) -> Result<Value> {
+        self.pos += 1;
+
+        match self.peek() {
+            Some('{') => Ok(Value::Set(self.read_seq('}')?)),
+            Some('(') => Ok(Value::List(self.read_seq(')')?)),
+            Some('"') => {
+                self.pos += 1;
+                let start = self.pos;
+                loop {
+                    match self.next() {
+                        None => return self.error("unterminated regex"),
+                        Some('\\') => {
+                            self.next();
+                        }
+                        Some('"') => break,
+                        Some(_) => (),
+                    }
+                }
+                Ok(Value::Regex(
+                    self.chars[start..self.pos - 1].iter().collect(),
+                ))
+            }
+            Some('_') => {
+                self.pos += 1;
+                self.read_value()?;
+                self.read_value()
+            }
+            Some('\'') => {
+                self.pos += 1;
+                self.skip_whitespace();
+                Ok(Value::Var(self.read_token()))
+            }
+            Some('#') => {
+                self.pos += 1;
+                match self.read_token().as_str() {
+                    "Inf" => Ok(Value::Float(f64::INFINITY)),
+                    "-Inf" => Ok(Value::Float(f64::NEG_INFINITY)),
+                    "NaN" => Ok(Value::Float(f64::NAN)),
+                    s => self.error(&format!("unknown symbolic value ##{}", s)),
+                }
+            }
+            Some(':') => {
+                self.pos += 1;
+                let ns = self.read_token();
+                self.skip_whitespace();
+                if self.peek() != Some('{') {
+                    return self.error("namespaced map must be followed by a map");
+                }
+                self.read_map(Some(&ns))
+            }
+            Some('?') => {
+                self.pos += 1;
+                let tag = if self.peek() == Some('@') {
+                    self.pos += 1;
+                    "?@"
+                } else {
+                    "?"
+                };
+                Ok(Value::Tagged(tag.to_string(), Box::new(self.read_value()?)))
+            }
+            Some('<') => {
+                // unreadable form, e.g. #<Object ...>
+                let start = self.pos - 1;
+                while let Some(c) = self.next() {
+                    if c == '>' {
+                        break;
+                    }
+                }
+                Ok(Value::String(self.chars[start..self.pos].iter().collect()))
+            }
+            Some(_) => {
+                let tag = self.read_token();
+                if tag.is_empty() {
+                    return self.error("invalid dispatch character");
+                }
+                Ok(Value::Tagged(tag, Box::new(self.read_value()?)))
+            }
+            None => self.error("unexpected end of input after '#'"),
+        }
+    }
+
+    fn parse_token(&self, token: &str) -> Result<Value> {
+        let mut chars = token.chars();
+        let first = chars.next().unwrap_or(' ');
+        let second = chars.next();
+
+        if first.is_ascii_digit()
+            || ((first == '+' || first == '-') && second.is_some_and(|c| c.is_ascii_digit()))
+        {
+            return self.parse_number(token);
+        }
+
+        match token {
+            "nil" => Ok(Value::Nil),
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            _ if first == ':' => Ok(Value::Keyword(token[1..].to_string())),
+            _ => Ok(Value::Symbol(token.to_string())),
+        }
+    }
+
+    fn parse_number(&self, token: &str) -> Result<Value> {
+        let (negative, digits) = match token.as_bytes()[0] {
+            b'-' => (true, &token[1..]),
+            b'+' => (false, &token[1..]),
+            _ => (false, token),
+        };
+        let sign = if negative { -1 } else { 1 };
+
+        let value = if token.ends_with('N') {
+            Some(Value::BigInt(token.to_string()))
+        } else if token.ends_with('M') {
+            Some(Value::BigDec(token.to_string()))
+        } else if token.contains('/') {
+            Some(Value::Ratio(token.to_string()))
+        } else if digits.starts_with("0x") || digits.starts_with("0X") {
+            i64::from_str_radix(&digits[2..], 16)
+                .ok()
+                .map(|i| Value::Integer(sign * i))
+        } else if let Some(r) = digits.find(['r', 'R']) {
+            digits[..r]
+                .parse::<u32>()
+                .ok()
+                .filter(|radix| (2..=36).contains(radix))
+                .and_then(|radix| i64::from_str_radix(&digits[r + 1..], radix).ok())
+                .map(|i| Value::Integer(sign * i))
+        } else if digits.contains(['.', 'e', 'E']) {
+            token.parse::<f64>().ok().map(Value::Float)
+        } else {
+            match token.parse::<i64>() {
+                Ok(i) => Some(Value::Integer(i)),
+                Err(_) if digits.chars().all(|c| c.is_ascii_digit()) => {
+                    Some(Value::BigInt(format!("{}N", token)))
+                }
+                Err(_) => None,
+            }
+        };
+
+        match value {
+            Some(value) => Ok(value),
+            None => self.error(&format!("invalid number {}", token)),
+        }
+    }
+}
+
+/// Parses the first value of `s`.
+pub fn parse(s: &str) -> Result<Value> {
+    match Parser::new(s).read() {
+        Some(result) => result,
+        None => Err(Error {
+            pos: 0,
+            message: "no value to parse".to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kw(name: &str) -> Value {
+        Value::Keyword(name.to_string())
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            parse(r#""a\"b\\c\n\t\r é \101 \0""#),
+            Ok(Value::String("a\"b\\c\n\t\r é A \0".to_string()))
+        );
+        assert!(parse(r#""abc"#).is_err());
+        assert!(parse(r#""\uZZZZ""#).is_err());
+
+        let s = Value::String("\"q\" \\ \n\t\r \u{1b}[31m é".to_string());
+        assert_eq!(s.to_string(), "\"\\\"q\\\" \\\\ \\n\\t\\r \u{1b}[31m é\"");
+        assert_eq!(parse(&s.to_string()), Ok(s));
+    }
+
+    #[test]
+    fn chars() {
+        assert_eq!(parse(r"\a"), Ok(Value::Char('a')));
+        assert_eq!(parse(r"\newline"), Ok(Value::Char('\n')));
+        assert_eq!(parse(r"\é"), Ok(Value::Char('é')));
+        assert_eq!(parse(r"\o101"), Ok(Value::Char('A')));
+        assert_eq!(parse(r"\("), Ok(Value::Char('(')));
+        assert_eq!(
+            parse(r"[\( \) \space]"),
+            Ok(Value::Vector(vec![
+                Value::Char('('),
+                Value::Char(')'),
+                Value::Char(' ')
+            ]))
+        );
+        assert!(parse(r"\foo").is_err());
+        assert_eq!(Value::Char(' ').to_string(), r"\space");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse("-42"), Ok(Value::Integer(-42)));
+        assert_eq!(parse("0x1F"), Ok(Value::Integer(31)));
+        assert_eq!(parse("2r101"), Ok(Value::Integer(5)));
+        assert_eq!(parse("1.5e3"), Ok(Value::Float(1500.0)));
+        assert_eq!(parse("1/3"), Ok(Value::Ratio("1/3".to_string())));
+        assert_eq!(parse("-22/7"), Ok(Value::Ratio("-22/7".to_string())));
+        assert_eq!(parse("123N"), Ok(Value::BigInt("123N".to_string())));
+        assert_eq!(
+            parse("99999999999999999999"),
+            Ok(Value::BigInt("99999999999999999999N".to_string()))
+        );
+        assert_eq!(parse("1.5M"), Ok(Value::BigDec("1.5M".to_string())));
+        assert_eq!(parse("##Inf"), Ok(Value::Float(f64::INFINITY)));
+        assert!(parse("1x").is_err());
+        assert_eq!(Value::Float(2.0).to_string(), "2.0");
+    }
+
+    #[test]
+    fn tagged_literals() {
+        assert_eq!(
+            parse(r#"#inst "2020-01-01""#),
+            Ok(Value::Tagged(
+                "inst".to_string(),
+                Box::new(Value::String("2020-01-01".to_string()))
+            ))
+        );
+
+        let obj = parse(r#"#object[java.lang.Object 0x1a "java.lang.Object@1a"]"#).unwrap();
+        match &obj {
+            Value::Tagged(tag, val) => {
+                assert_eq!(tag, "object");
+                assert_eq!(val.as_slice().map(<[Value]>::len), Some(3));
+            }
+            _ => panic!("not tagged: {}", obj),
+        }
+
+        let err = parse(r#"#error {:cause "boom" :via []}"#).unwrap();
+        assert_eq!(err.get("cause").and_then(Value::as_str), Some("boom"));
+        assert_eq!(err.to_string(), r#"#error {:cause "boom", :via []}"#);
+
+        assert_eq!(parse("#'user/x"), Ok(Value::Var("user/x".to_string())));
+    }
+
+    #[test]
+    fn discard() {
+        assert_eq!(parse("#_ 1 2"), Ok(Value::Integer(2)));
+        assert_eq!(
+            parse("[1 #_ 2 #_ #_ 3 4 5 #_(6)]"),
+            Ok(Value::Vector(vec![Value::Integer(1), Value::Integer(5)]))
+        );
+        assert_eq!(
+            parse("{:a #_ :b 1}"),
+            Ok(Value::Map(vec![(kw("a"), Value::Integer(1))]))
+        );
+    }
+
+    #[test]
+    fn collections() {
+        let val = parse("{:a [1 (2 #{3})], :b {:c nil} ; comment\n :d ^:meta sym}").unwrap();
+        assert_eq!(
+            val,
+            Value::Map(vec![
+                (
+                    kw("a"),
+                    Value::Vector(vec![
+                        Value::Integer(1),
+                        Value::List(vec![Value::Integer(2), Value::Set(vec![Value::Integer(3)])])
+                    ])
+                ),
+                (kw("b"), Value::Map(vec![(kw("c"), Value::Nil)])),
+                (kw("d"), Value::Symbol("sym".to_string())),
+            ])
+        );
+        assert_eq!(val.to_string(), "{:a [1 (2 #{3})], :b {:c nil}, :d sym}");
+
+        assert_eq!(
+            parse("#:foo{:a 1 :bar/b 2 :_/c 3}"),
+            Ok(Value::Map(vec![
+                (kw("foo/a"), Value::Integer(1)),
+                (kw("bar/b"), Value::Integer(2)),
+                (kw("c"), Value::Integer(3)),
+            ]))
+        );
+        assert!(parse("{:a}").is_err());
+        assert!(parse("[1 2").is_err());
+        assert!(parse(")").is_err());
+    }
+
+    #[test]
+    fn pretty() {
+        let val = parse("{:a [1 2 3] :b \"x\"}").unwrap();
+        assert_eq!(val.pretty(80), "{:a [1 2 3], :b \"x\"}");
+        assert_eq!(val.pretty(10), "{:a [1\n     2\n     3],\n :b \"x\"}");
+    }
+}
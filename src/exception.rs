@@ -0,0 +1,675 @@
+//! Structured exceptions from `Throwable->map` data.
+//!
+//! Both REPLs report exceptions as EDN: pREPL as the `Throwable->map` of the
+//! exception, nREPL as the printed `#error {...}` form of the throwable.
+//! `ExceptionInfo` keeps the cause chain, ex-data and stack frames of either
+//! and triages them the way `clojure.main/ex-triage` and `ex-str` do.
+
+use crate::edn;
+use crate::explain;
+
+/// Source files that mean "no file", e.g. code typed at the REPL.
+const NO_SOURCE: [&str; 2] = ["NO_SOURCE_FILE", "NO_SOURCE_PATH"];
+
+/// Munged characters of Clojure names in Java class names.
+const MUNGED: [(&str, &str); 25] = [
+    ("_QMARK_", "?"),
+    ("_BANG_", "!"),
+    ("_PLUS_", "+"),
+    ("_GT_", ">"),
+    ("_LT_", "<"),
+    ("_EQ_", "="),
+    ("_STAR_", "*"),
+    ("_SLASH_", "/"),
+    ("_COLON_", ":"),
+    ("_SINGLEQUOTE_", "'"),
+    ("_AMPERSAND_", "&"),
+    ("_PERCENT_", "%"),
+    ("_CARET_", "^"),
+    ("_SHARP_", "#"),
+    ("_BAR_", "|"),
+    ("_LBRACK_", "["),
+    ("_RBRACK_", "]"),
+    ("_LBRACE_", "{"),
+    ("_RBRACE_", "}"),
+    ("_TILDE_", "~"),
+    ("_BSLASH_", "\\"),
+    ("_DOUBLEQUOTE_", "\""),
+    ("_AT_", "@"),
+    ("_DOT_", "."),
+    ("_", "-"),
+];
+
+/// Class name prefixes of REPL servers and other development tooling.
+const TOOLING: [&str; 6] = [
+    "nrepl.",
+    "cider.",
+    "refactor_nrepl.",
+    "clojure.core.server$",
+    "clojure.main$repl",
+    "clojure.main$eval_opt",
+];
+
+/// Class name prefixes of the JVM and its libraries.
+const JAVA: [&str; 6] = ["java.", "javax.", "jdk.", "sun.", "com.sun.", "org.jcp."];
+
+/// Clojure name of a munged class name, e.g. `clojure.core/map/fn--5862`
+/// for `clojure.core$map$fn__5862`, like `clojure.main/demunge`.
+pub fn demunge(class: &str) -> String {
+    let mut name = String::new();
+    let mut rest = class;
+
+    while let Some(c) = rest.chars().next() {
+        match MUNGED.iter().find(|(munged, _)| rest.starts_with(munged)) {
+            Some((munged, demunged)) => {
+                name.push_str(demunged);
+                rest = &rest[munged.len()..];
+            }
+            None => {
+                name.push(if c == '$' { '/' } else { c });
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+
+    name
+}
+
+/// What a stack frame belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Project,
+    Clojure,
+    Java,
+    Tooling,
+    /// same function as the frame before, e.g. `invoke` calling `invokeStatic`
+    Dup,
+}
+
+impl FrameKind {
+    /// Kind named `name` in `g:redbush_trace_filter`.
+    pub fn from_name(name: &str) -> Option<FrameKind> {
+        match name {
+            "project" => Some(FrameKind::Project),
+            "clojure" => Some(FrameKind::Clojure),
+            "java" => Some(FrameKind::Java),
+            "tooling" => Some(FrameKind::Tooling),
+            "dup" => Some(FrameKind::Dup),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FrameKind::Project => "project",
+            FrameKind::Clojure => "clojure",
+            FrameKind::Java => "java",
+            FrameKind::Tooling => "tooling",
+            FrameKind::Dup => "dup",
+        }
+    }
+
+    fn of_class(class: &str) -> FrameKind {
+        if TOOLING.iter().any(|prefix| class.starts_with(prefix)) {
+            FrameKind::Tooling
+        } else if class.starts_with("clojure.") {
+            FrameKind::Clojure
+        } else if JAVA.iter().any(|prefix| class.starts_with(prefix)) {
+            FrameKind::Java
+        } else {
+            FrameKind::Project
+        }
+    }
+}
+
+/// Stack trace element, `[class method file line]` in `Throwable->map`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub class: String,
+    pub method: String,
+    pub file: String,
+    pub line: i64,
+    /// demunged function name, or `class.method` for Java frames
+    pub name: String,
+    pub kind: FrameKind,
+}
+
+fn is_invoke(method: &str) -> bool {
+    matches!(
+        method,
+        "invoke" | "invokeStatic" | "invokePrim" | "doInvoke" | "applyTo" | "applyToHelper"
+    )
+}
+
+impl Frame {
+    fn from_edn(val: &edn::Value) -> Option<Frame> {
+        let elems = val.as_slice()?;
+        let text = |i: usize| match elems.get(i) {
+            Some(edn::Value::Symbol(s)) | Some(edn::Value::String(s)) => s.to_string(),
+            _ => "".to_string(),
+        };
+        let class = text(0);
+        let method = text(1);
+
+        Some(Frame {
+            name: Frame::name(&class, &method),
+            kind: FrameKind::of_class(&class),
+            file: text(2),
+            line: elems.get(3).and_then(edn::Value::as_i64).unwrap_or(0),
+            class,
+            method,
+        })
+    }
+
+    /// `clojure.core/map/fn` for `clojure.core$map$fn__5862.invoke`, anonymous
+    /// function numbers being dropped.
+    fn name(class: &str, method: &str) -> String {
+        if !class.contains('$') {
+            return format!("{}.{}", class, method);
+        }
+
+        let name = demunge(class)
+            .split('/')
+            .map(|part| match part.rfind("--") {
+                Some(i) if part[i + 2..].chars().all(|c| c.is_ascii_digit()) => &part[..i],
+                _ => part,
+            })
+            .collect::<Vec<&str>>()
+            .join("/");
+
+        if is_invoke(method) {
+            name
+        } else {
+            format!("{}.{}", name, method)
+        }
+    }
+
+    /// Whether the frame is in the same function as `other`, e.g. `invoke`
+    /// calling `invokeStatic`. Compared by munged class, as `name` does not
+    /// tell anonymous functions apart.
+    fn same_fn(&self, other: &Frame) -> bool {
+        self.class == other.class
+            && self.file == other.file
+            && (self.method == other.method || is_invoke(&self.method) && is_invoke(&other.method))
+    }
+
+    /// Name of the function the frame is in, like `clojure.main/ex-triage`
+    /// reports it, e.g. `foo.core/bar` for `foo.core$bar.invoke`.
+    pub fn symbol(&self) -> String {
+        if is_invoke(&self.method) {
+            demunge(&self.class)
+        } else {
+            format!("{}.{}", demunge(&self.class), self.method)
+        }
+    }
+
+    /// Frames of Clojure and Java internals, skipped when locating an error.
+    fn is_core(&self) -> bool {
+        [
+            "clojure.lang.",
+            "clojure.core$",
+            "clojure.main$",
+            "java.",
+            "jdk.",
+            "sun.",
+        ]
+        .iter()
+        .any(|prefix| self.class.starts_with(prefix))
+    }
+}
+
+impl std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}:{})", self.name, self.file, self.line)
+    }
+}
+
+/// One exception of the cause chain, an entry of `:via`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cause {
+    pub class: String,
+    pub message: String,
+    pub data: Option<edn::Value>,
+    pub at: Option<Frame>,
+}
+
+impl Cause {
+    fn from_edn(val: &edn::Value) -> Cause {
+        Cause {
+            class: val
+                .get("type")
+                .and_then(edn::Value::as_name)
+                .unwrap_or("")
+                .to_string(),
+            message: val
+                .get("message")
+                .and_then(edn::Value::as_str)
+                .unwrap_or("")
+                .to_string(),
+            data: val.get("data").cloned(),
+            at: val.get("at").and_then(Frame::from_edn),
+        }
+    }
+
+    /// Value of `:clojure.error/<key>` in the ex-data.
+    fn error_key(&self, key: &str) -> Option<&edn::Value> {
+        self.data
+            .as_ref()
+            .and_then(|data| data.get(&format!("clojure.error/{}", key)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExceptionInfo {
+    /// causes, outermost first
+    pub via: Vec<Cause>,
+    /// message of the root cause
+    pub cause: String,
+    /// `:read-source`, `:compile-syntax-check`, `:execution` etc.
+    pub phase: String,
+    pub source: Option<String>,
+    pub line: Option<i64>,
+    pub column: Option<i64>,
+    pub symbol: Option<String>,
+    pub trace: Vec<Frame>,
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+impl ExceptionInfo {
+    /// Exception from its `Throwable->map` or `#error` EDN text.
+    pub fn parse(s: &str) -> Option<ExceptionInfo> {
+        match edn::parse(s) {
+            Ok(val) => ExceptionInfo::from_edn(&val),
+            Err(e) => {
+                log::warn!("Failed to parse exception: {}: {}", e, s);
+                None
+            }
+        }
+    }
+
+    pub fn from_edn(val: &edn::Value) -> Option<ExceptionInfo> {
+        let via: Vec<Cause> = val
+            .get("via")?
+            .as_slice()?
+            .iter()
+            .map(Cause::from_edn)
+            .collect();
+        let mut trace: Vec<Frame> = val
+            .get("trace")
+            .and_then(edn::Value::as_slice)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(Frame::from_edn)
+            .collect();
+        for i in 1..trace.len() {
+            if trace[i].same_fn(&trace[i - 1]) {
+                trace[i].kind = FrameKind::Dup;
+            }
+        }
+        let cause = match val.get("cause").and_then(edn::Value::as_str) {
+            Some(s) => s.to_string(),
+            None => via
+                .last()
+                .map(|c| c.message.to_string())
+                .unwrap_or_default(),
+        };
+        // the printed `#error` form has no `:phase`, the ex-data has
+        let phase = val
+            .get("phase")
+            .or_else(|| via.first().and_then(|c| c.error_key("phase")))
+            .and_then(edn::Value::as_name)
+            .unwrap_or("execution")
+            .to_string();
+
+        let mut info = ExceptionInfo {
+            via,
+            cause,
+            phase,
+            source: None,
+            line: None,
+            column: None,
+            symbol: None,
+            trace,
+        };
+        info.locate();
+
+        Some(info)
+    }
+
+    /// Exception known only by its class, e.g. nREPL `ex` without the throwable.
+    pub fn from_class(class: &str) -> ExceptionInfo {
+        ExceptionInfo {
+            via: vec![Cause {
+                class: class.to_string(),
+                message: "".to_string(),
+                data: None,
+                at: None,
+            }],
+            cause: "".to_string(),
+            phase: "execution".to_string(),
+            source: None,
+            line: None,
+            column: None,
+            symbol: None,
+            trace: vec![],
+        }
+    }
+
+    /// Sets the error location like `clojure.main/ex-triage`: from the
+    /// `:clojure.error/*` ex-data when compiling or reading, from the first
+    /// non-core stack frame otherwise.
+    fn locate(&mut self) {
+        let top = self.via.first().cloned();
+        let error_key = |key: &str| top.as_ref().and_then(|c| c.error_key(key));
+
+        match self.phase.as_str() {
+            "execution" | "read-eval-result" | "print-eval-result" => {
+                let frame = if self.phase == "execution" {
+                    self.trace.iter().find(|f| !f.is_core())
+                } else {
+                    self.trace.first()
+                };
+                if let Some(frame) = frame {
+                    self.source = Some(frame.file.to_string());
+                    self.line = Some(frame.line).filter(|l| *l > 0);
+                    self.symbol = Some(frame.symbol());
+                }
+            }
+            _ => {
+                // reader errors carry their location on the second cause
+                let data = if self.phase == "read-source" {
+                    self.via.get(1).and_then(|c| c.data.as_ref())
+                } else {
+                    None
+                };
+                let key = |key: &str| {
+                    error_key(key)
+                        .or_else(|| data.and_then(|d| d.get(&format!("clojure.error/{}", key))))
+                };
+
+                self.source = key("source").and_then(edn::Value::as_str).map(String::from);
+                self.line = key("line").and_then(edn::Value::as_i64);
+                self.column = key("column").and_then(edn::Value::as_i64);
+                self.symbol = key("symbol").map(|s| match s {
+                    edn::Value::String(s) => s.to_string(),
+                    s => s.to_string(),
+                });
+            }
+        }
+
+        if self
+            .source
+            .as_ref()
+            .is_some_and(|s| s.is_empty() || NO_SOURCE.contains(&s.as_str()))
+        {
+            self.source = None;
+        }
+    }
+
+    /// Class of the root cause.
+    pub fn class(&self) -> &str {
+        self.via.last().map(|c| c.class.as_str()).unwrap_or("")
+    }
+
+    /// Location as `(foo.clj:12:3)`, `REPL` standing for unknown source.
+    pub fn location(&self) -> String {
+        let source = self.source.as_deref().map(file_name).unwrap_or("REPL");
+        match self.column {
+            Some(column) => format!("{}:{}:{}", source, self.line.unwrap_or(1), column),
+            None => format!("{}:{}", source, self.line.unwrap_or(1)),
+        }
+    }
+
+    /// One-line summary, as printed by the Clojure REPL.
+    pub fn summary(&self) -> String {
+        let class = self.class();
+        let simple_class = class.rsplit('.').next().unwrap_or(class);
+        let cause_type = match simple_class {
+            "" | "Exception" | "RuntimeException" => "".to_string(),
+            _ => format!(" ({})", simple_class),
+        };
+        let symbol = match &self.symbol {
+            Some(s) => format!("{} ", s),
+            None => "".to_string(),
+        };
+        let loc = self.location();
+
+        match self.phase.as_str() {
+            "read-source" => format!("Syntax error reading source at ({}).", loc),
+            "macro-syntax-check" => format!("Syntax error macroexpanding {}at ({}).", symbol, loc),
+            "macroexpansion" => format!(
+                "Unexpected error{} macroexpanding {}at ({}).",
+                cause_type, symbol, loc
+            ),
+            "compile-syntax-check" => {
+                format!(
+                    "Syntax error{} compiling {}at ({}).",
+                    cause_type, symbol, loc
+                )
+            }
+            "compilation" => format!(
+                "Unexpected error{} compiling {}at ({}).",
+                cause_type, symbol, loc
+            ),
+            "read-eval-result" => format!(
+                "Error reading eval result{} at {}({}).",
+                cause_type, symbol, loc
+            ),
+            "print-eval-result" => format!(
+                "Error printing return value{} at {}({}).",
+                cause_type, symbol, loc
+            ),
+            _ => format!("Execution error{} at {}({}).", cause_type, symbol, loc),
+        }
+    }
+
+    /// Root cause message followed by the cause chain with the ex-data of
+    /// each cause, and the stack trace. Runs of frames of the `hidden` kinds
+    /// are collapsed into a single line.
+    pub fn detail(&self, hidden: &[FrameKind]) -> String {
+        let mut lines = vec![];
+
+        if !self.cause.is_empty() {
+            lines.push(self.cause.to_string());
+        }
+        for cause in self.via.iter() {
+            let mut line = cause.class.to_string();
+            if !cause.message.is_empty() {
+                line.push_str(&format!(": {}", cause.message));
+            }
+            lines.push(line);
+            if let Some(data) = &cause.data {
+                match explain::problems(data) {
+                    Some(problems) => {
+                        lines.extend(problems.into_iter().map(|p| format!("  {}", p)))
+                    }
+                    None => lines.push(format!("  {}", data)),
+                }
+            }
+        }
+
+        let mut hidden_cnt = 0;
+        for frame in self.trace.iter() {
+            if hidden.contains(&frame.kind) {
+                hidden_cnt += 1;
+                continue;
+            }
+            if hidden_cnt > 0 {
+                lines.push(format!("  ... {} frames hidden", hidden_cnt));
+                hidden_cnt = 0;
+            }
+            lines.push(format!("  {}", frame));
+        }
+        if hidden_cnt > 0 {
+            lines.push(format!("  ... {} frames hidden", hidden_cnt));
+        }
+
+        lines.join("\n")
+    }
+
+    /// Lines of the exception browser, with the 1-based line and index of
+    /// each stack frame. Sections are indented so that they fold by indent.
+    pub fn browse(&self) -> (Vec<String>, Vec<(i64, usize)>) {
+        let mut lines = vec![self.summary()];
+        let mut frames = vec![];
+
+        lines.extend(self.cause.lines().map(String::from));
+        lines.push(format!("Phase: {}", self.phase));
+
+        lines.push("Causes".to_string());
+        for cause in self.via.iter() {
+            lines.push(format!("  {}", cause.class));
+            lines.extend(cause.message.lines().map(|l| format!("    {}", l)));
+            if let Some(at) = &cause.at {
+                lines.push(format!("    at {}", at));
+            }
+            if let Some(data) = &cause.data {
+                lines.push("    ex-data".to_string());
+                match explain::problems(data) {
+                    Some(problems) => {
+                        lines.extend(problems.into_iter().map(|p| format!("      {}", p)))
+                    }
+                    None => match data {
+                        edn::Value::Map(entries) => {
+                            lines.extend(entries.iter().map(|(k, v)| format!("      {} {}", k, v)))
+                        }
+                        data => lines.push(format!("      {}", data)),
+                    },
+                }
+            }
+        }
+
+        lines.push("Trace".to_string());
+        for (i, frame) in self.trace.iter().enumerate() {
+            lines.push(format!("  {} [{}]", frame, frame.kind.name()));
+            frames.push((lines.len() as i64, i));
+        }
+
+        (lines, frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dup_frames() {
+        let val = edn::parse(
+            r#"{:via [{:type java.lang.Exception :message "x"}]
+                :trace [[foo.core$bar$fn__12 invoke "core.clj" 3]
+                        [foo.core$bar$fn__10 invoke "core.clj" 3]
+                        [foo.core$bar$fn__10 invokeStatic "core.clj" 3]
+                        [foo.core$bar invoke "core.clj" 2]]}"#,
+        )
+        .unwrap();
+        let info = ExceptionInfo::from_edn(&val).unwrap();
+        let kinds: Vec<FrameKind> = info.trace.iter().map(|f| f.kind).collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                FrameKind::Project,
+                FrameKind::Project,
+                FrameKind::Dup,
+                FrameKind::Project
+            ]
+        );
+        assert_eq!(info.trace[0].name, "foo.core/bar/fn");
+    }
+
+    #[test]
+    fn compile_syntax_check() {
+        // nREPL prints the throwable as `#error`, the ex-data as namespaced map
+        let info = ExceptionInfo::parse(
+            r#"#error {
+                :cause "Unable to resolve symbol: foo in this context"
+                :via [{:type clojure.lang.Compiler$CompilerException
+                       :message "Syntax error compiling at (src/app/core.clj:12:3)."
+                       :data #:clojure.error{:phase :compile-syntax-check
+                                             :line 12
+                                             :column 3
+                                             :source "src/app/core.clj"}
+                       :at [clojure.lang.Compiler analyze "Compiler.java" 6808]}
+                      {:type java.lang.RuntimeException
+                       :message "Unable to resolve symbol: foo in this context"
+                       :at [clojure.lang.Util runtimeException "Util.java" 221]}]
+                :trace [[clojure.lang.Util runtimeException "Util.java" 221]]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.phase, "compile-syntax-check");
+        assert_eq!(info.source.as_deref(), Some("src/app/core.clj"));
+        assert_eq!((info.line, info.column), (Some(12), Some(3)));
+        assert_eq!(info.location(), "core.clj:12:3");
+        assert_eq!(info.summary(), "Syntax error compiling at (core.clj:12:3).");
+        assert_eq!(info.cause, "Unable to resolve symbol: foo in this context");
+    }
+
+    #[test]
+    fn read_source() {
+        // `Throwable->map` as pREPL sends it, the location on the second cause
+        let info = ExceptionInfo::parse(
+            r#"{:via [{:type clojure.lang.Compiler$CompilerException
+                       :message "Syntax error reading source at (REPL:3:1)."
+                       :data {:clojure.error/phase :read-source}}
+                      {:type java.lang.RuntimeException
+                       :message "EOF while reading"
+                       :data {:clojure.error/line 3
+                              :clojure.error/column 1
+                              :clojure.error/source "NO_SOURCE_PATH"}}]
+                :trace []
+                :cause "EOF while reading"
+                :phase :read-source}"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.phase, "read-source");
+        assert_eq!(info.source, None);
+        assert_eq!((info.line, info.column), (Some(3), Some(1)));
+        assert_eq!(info.summary(), "Syntax error reading source at (REPL:3:1).");
+    }
+
+    #[test]
+    fn execution() {
+        let info = ExceptionInfo::parse(
+            r#"{:via [{:type clojure.lang.ExceptionInfo
+                       :message "boom"
+                       :data {:id 1}}]
+                :trace [[clojure.core$ex_info invokeStatic "core.clj" 4617]
+                        [clojure.lang.AFn applyToHelper "AFn.java" 156]
+                        [app.core$do_it_BANG_ invoke "core.clj" 7]
+                        [app.main$_main invokeStatic "main.clj" 3]]
+                :cause "boom"
+                :phase :execution}"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.source.as_deref(), Some("core.clj"));
+        assert_eq!((info.line, info.column), (Some(7), None));
+        assert_eq!(info.symbol.as_deref(), Some("app.core/do-it!"));
+        assert_eq!(
+            info.summary(),
+            "Execution error (ExceptionInfo) at app.core/do-it! (core.clj:7)."
+        );
+
+        // the printed `#error` form has no phase, execution is assumed
+        let info = ExceptionInfo::parse(
+            r#"#error {:cause "Divide by zero"
+                       :via [{:type java.lang.ArithmeticException :message "Divide by zero"}]
+                       :trace [[clojure.lang.Numbers divide "Numbers.java" 188]
+                               [user$eval1 invokeStatic "NO_SOURCE_FILE" 1]]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(info.phase, "execution");
+        assert_eq!(info.source, None);
+        assert_eq!(
+            info.summary(),
+            "Execution error (ArithmeticException) at user/eval1 (REPL:1)."
+        );
+    }
+}
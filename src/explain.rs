@@ -0,0 +1,170 @@
+//! Readable clojure.spec and Malli problems from ex-data.
+//!
+//! spec puts its explain data in the ex-data under
+//! `:clojure.spec.alpha/problems`. Malli throws with `{:type .., :data ..}`,
+//! the explanation being `:explain` of the data, or the data itself.
+
+use crate::edn;
+
+/// Longest printed value before it is truncated.
+const MAX_VALUE_LEN: usize = 80;
+
+fn truncate(s: &str, max: usize) -> String {
+    match s.char_indices().nth(max) {
+        Some((i, _)) => format!("{}…", &s[..i]),
+        None => s.to_string(),
+    }
+}
+
+fn path(val: Option<&edn::Value>) -> String {
+    match val {
+        Some(path) if path.as_slice().is_some_and(|p| !p.is_empty()) => path.to_string(),
+        _ => "[]".to_string(),
+    }
+}
+
+fn line(path: String, pred: String, value: Option<&edn::Value>) -> String {
+    match value {
+        Some(value) => format!(
+            "at {}: {}, got {}",
+            path,
+            pred,
+            truncate(&value.to_string(), MAX_VALUE_LEN)
+        ),
+        None => format!("at {}: {}", path, pred),
+    }
+}
+
+fn spec_problems(data: &edn::Value) -> Option<Vec<String>> {
+    let problems = data.get("clojure.spec.alpha/problems")?.as_slice()?;
+
+    Some(
+        problems
+            .iter()
+            .map(|problem| {
+                let pred = match problem.get("reason").and_then(edn::Value::as_str) {
+                    Some(reason) => reason.to_string(),
+                    None => format!(
+                        "should satisfy {}",
+                        problem.get("pred").unwrap_or(&edn::Value::Nil)
+                    ),
+                };
+                let in_path = problem.get("in").or_else(|| problem.get("path"));
+                line(path(in_path), pred, problem.get("val"))
+            })
+            .collect(),
+    )
+}
+
+fn malli_errors(data: &edn::Value) -> Option<Vec<String>> {
+    let explain = data
+        .get("data")
+        .and_then(|d| d.get("explain"))
+        .or_else(|| data.get("explain"))
+        .or_else(|| data.get("data"))
+        .unwrap_or(data);
+    let errors = explain.get("errors")?.as_slice()?;
+    explain.get("schema")?;
+
+    Some(
+        errors
+            .iter()
+            .map(|error| {
+                let kind = error.get("type").and_then(edn::Value::as_name);
+                let pred = match kind {
+                    Some("malli.core/missing-key") => "missing required key".to_string(),
+                    Some("malli.core/extra-key") => "disallowed key".to_string(),
+                    _ => format!(
+                        "should satisfy {}",
+                        error.get("schema").unwrap_or(&edn::Value::Nil)
+                    ),
+                };
+                let in_path = error.get("in").or_else(|| error.get("path"));
+                match kind {
+                    Some("malli.core/missing-key") => line(path(in_path), pred, None),
+                    _ => line(path(in_path), pred, error.get("value")),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// One line per spec or Malli problem in ex-data `data`, `None` if the data
+/// is neither.
+pub fn problems(data: &edn::Value) -> Option<Vec<String>> {
+    spec_problems(data).or_else(|| malli_errors(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems_of(data: &str) -> Option<Vec<String>> {
+        problems(&edn::parse(data).unwrap())
+    }
+
+    #[test]
+    fn spec() {
+        assert_eq!(
+            problems_of(
+                r#"{:clojure.spec.alpha/problems
+                    ({:path [:age] :pred clojure.core/pos-int? :val -1 :via [:app/person] :in [:age]}
+                     {:path [] :pred (clojure.core/fn [%] (clojure.core/contains? % :name)) :val {:age -1} :in []}
+                     {:path [:id] :reason "Extra input" :val (1 2) :in [:id]})
+                    :clojure.spec.alpha/spec :app/person
+                    :clojure.spec.alpha/value {:age -1}}"#
+            ),
+            Some(vec![
+                "at [:age]: should satisfy clojure.core/pos-int?, got -1".to_string(),
+                "at []: should satisfy (clojure.core/fn [%] (clojure.core/contains? % :name)), got {:age -1}"
+                    .to_string(),
+                "at [:id]: Extra input, got (1 2)".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn malli() {
+        assert_eq!(
+            problems_of(
+                r#"{:type :malli.core/invalid-input
+                    :data {:explain {:schema [:map [:id :int] [:name :string]]
+                                     :value {:id "x"}
+                                     :errors ({:path [:id] :in [:id] :schema :int :value "x"}
+                                              {:path [:name]
+                                               :in [:name]
+                                               :schema [:map [:id :int] [:name :string]]
+                                               :type :malli.core/missing-key})}}}"#
+            ),
+            Some(vec![
+                r#"at [:id]: should satisfy :int, got "x""#.to_string(),
+                "at [:name]: missing required key".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn other_data() {
+        assert_eq!(problems_of("{:id 1}"), None);
+        assert_eq!(problems_of("nil"), None);
+    }
+
+    #[test]
+    fn long_values_are_truncated() {
+        let value = format!("\"{}\"", "x".repeat(100));
+        let problems = problems_of(&format!(
+            "{{:clojure.spec.alpha/problems [{{:path [] :pred clojure.core/int? :val {} :in []}}]}}",
+            value
+        ))
+        .unwrap();
+
+        assert_eq!(
+            problems,
+            vec![format!(
+                "at []: should satisfy clojure.core/int?, got {}…",
+                &value[..MAX_VALUE_LEN]
+            )]
+        );
+        assert_eq!(truncate("short", MAX_VALUE_LEN), "short");
+    }
+}
@@ -0,0 +1,216 @@
+//! Filtering of REPL output before it is shown in the log buffer.
+//!
+//! Output is filtered by whole lines, as reassembled by `LineAssembler`.
+//! Lines of `out` and `err` are kept if they match the include pattern of
+//! their stream and do not match the exclude pattern, and identical
+//! consecutive lines are collapsed into one with a repeat count.
+
+use crate::logbuf::{Kind, Styles};
+use regex::Regex;
+
+/// Include and exclude patterns of one output stream.
+#[derive(Debug, Default)]
+pub struct StreamFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+}
+
+impl StreamFilter {
+    pub fn new(include: &str, exclude: &str) -> Result<StreamFilter, regex::Error> {
+        let pattern = |p: &str| match p {
+            "" => Ok(None),
+            p => Regex::new(p).map(Some),
+        };
+
+        Ok(StreamFilter {
+            include: pattern(include)?,
+            exclude: pattern(exclude)?,
+        })
+    }
+
+    fn keeps(&self, line: &str) -> bool {
+        let included = match &self.include {
+            Some(re) => re.is_match(line),
+            None => true,
+        };
+
+        included && !self.exclude.as_ref().is_some_and(|re| re.is_match(line))
+    }
+}
+
+/// Output to show after filtering.
+#[derive(Debug, PartialEq)]
+pub enum Shown {
+    /// complete line with the highlight groups of its byte ranges
+    Line(String, Styles),
+    /// the last line shown, of `len` bytes, was repeated `count` times in all
+    Repeat { len: usize, count: usize },
+}
+
+/// Repeat count following the text of a collapsed line, e.g. ` (×37)` of
+/// `line (×37)`.
+pub fn repeat_suffix(count: usize) -> String {
+    format!(" (×{})", count)
+}
+
+#[derive(Debug, Default)]
+pub struct Filter {
+    out: StreamFilter,
+    err: StreamFilter,
+    collapse: bool,
+    /// last line shown and how many times it has been repeated
+    last: Option<(Kind, String, usize)>,
+}
+
+impl Filter {
+    pub fn new(out: StreamFilter, err: StreamFilter, collapse: bool) -> Filter {
+        Filter {
+            out,
+            err,
+            collapse,
+            ..Filter::default()
+        }
+    }
+
+    /// Output to show of the whole line `line` of stream `kind`, `styles`
+    /// being the highlight groups of its byte ranges. `None` when the line
+    /// is filtered out.
+    pub fn line(&mut self, kind: Kind, line: &str, styles: Styles) -> Option<Shown> {
+        let filter = match kind {
+            Kind::Err => &self.err,
+            _ => &self.out,
+        };
+        if !filter.keeps(line) {
+            return None;
+        }
+
+        if self.collapse {
+            if let Some((last_kind, last, count)) = self.last.as_mut() {
+                if *last_kind == kind && last == line {
+                    *count += 1;
+                    return Some(Shown::Repeat {
+                        len: line.len(),
+                        count: *count,
+                    });
+                }
+            }
+            self.last = Some((kind, line.to_string(), 1));
+        }
+
+        Some(Shown::Line(line.to_string(), styles))
+    }
+
+    /// Ends the output, the next line is not collapsed with the ones before.
+    pub fn end(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines::LineAssembler;
+
+    fn shown(filter: &mut Filter, kind: Kind, lines: &[&str]) -> Vec<Option<Shown>> {
+        lines
+            .iter()
+            .map(|line| filter.line(kind, line, vec![]))
+            .collect()
+    }
+
+    fn line(text: &str) -> Option<Shown> {
+        Some(Shown::Line(text.to_string(), vec![]))
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let out = StreamFilter::new("^(INFO|WARN)", "noisy").unwrap();
+        let err = StreamFilter::new("", "^WARNING").unwrap();
+        let mut filter = Filter::new(out, err, false);
+        assert_eq!(
+            shown(
+                &mut filter,
+                Kind::Out,
+                &["INFO a", "DEBUG b", "WARN noisy c"]
+            ),
+            vec![line("INFO a"), None, None]
+        );
+        assert_eq!(
+            shown(&mut filter, Kind::Err, &["WARNING: x", "DEBUG b"]),
+            vec![None, line("DEBUG b")]
+        );
+        assert!(StreamFilter::new("(", "").is_err());
+    }
+
+    #[test]
+    fn identical_lines_are_collapsed() {
+        let mut filter = Filter::new(StreamFilter::default(), StreamFilter::default(), true);
+        assert_eq!(
+            shown(&mut filter, Kind::Out, &["line", "line", "line", "other"]),
+            vec![
+                line("line"),
+                Some(Shown::Repeat { len: 4, count: 2 }),
+                Some(Shown::Repeat { len: 4, count: 3 }),
+                line("other"),
+            ]
+        );
+        // the same line of the other stream or after the end is shown again
+        assert_eq!(
+            shown(&mut filter, Kind::Err, &["other"]),
+            vec![line("other")]
+        );
+        filter.end();
+        assert_eq!(
+            shown(&mut filter, Kind::Err, &["other"]),
+            vec![line("other")]
+        );
+
+        let mut filter = Filter::new(StreamFilter::default(), StreamFilter::default(), false);
+        assert_eq!(
+            shown(&mut filter, Kind::Out, &["line", "line"]),
+            vec![line("line"), line("line")]
+        );
+    }
+
+    #[test]
+    fn collapsed_line_shows_its_count() {
+        let mut filter = Filter::new(StreamFilter::default(), StreamFilter::default(), true);
+        let mut text = String::new();
+        for _ in 0..37 {
+            match filter.line(Kind::Out, "line", vec![]) {
+                Some(Shown::Line(line, _)) => text = line,
+                Some(Shown::Repeat { len, count }) => {
+                    text.truncate(len);
+                    text.push_str(&repeat_suffix(count));
+                }
+                None => (),
+            }
+        }
+        assert_eq!(text, "line (×37)");
+    }
+
+    #[test]
+    fn lines_split_across_chunks() {
+        let out = StreamFilter::new("", "DEBUG").unwrap();
+        let mut filter = Filter::new(out, StreamFilter::default(), true);
+        let mut lines = LineAssembler::default();
+        let mut shown = vec![];
+        for chunk in &["DE", "BUG x\nre", "peat\nrepeat", "\nrep"] {
+            for (line, styles) in lines.push(Kind::Out, chunk, &[]) {
+                shown.push(filter.line(Kind::Out, &line, styles));
+            }
+        }
+        for (kind, line, styles) in lines.end() {
+            shown.push(filter.line(kind, &line, styles));
+        }
+        assert_eq!(
+            shown,
+            vec![
+                None,
+                line("repeat"),
+                Some(Shown::Repeat { len: 6, count: 2 }),
+                line("rep"),
+            ]
+        );
+    }
+}
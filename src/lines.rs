@@ -0,0 +1,135 @@
+//! Reassembly of REPL output into whole lines.
+//!
+//! `out` and `err` arrive in chunks that may end in the middle of a line,
+//! and chunks of the two streams may interleave. The incomplete end of each
+//! stream is held until the rest of its line arrives or the output ends, so
+//! that a line of one stream is never broken by output of the other.
+
+use crate::logbuf::{Kind, Styles};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// `styles` within `range`, relative to its start.
+fn styles_of(styles: &[(Range<usize>, String)], range: Range<usize>) -> Styles {
+    styles
+        .iter()
+        .filter_map(|(r, group)| {
+            let start = r.start.max(range.start);
+            let end = r.end.min(range.end);
+            match start < end {
+                true => Some((start - range.start..end - range.start, group.to_string())),
+                false => None,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct LineAssembler {
+    /// incomplete lines by stream
+    partial: HashMap<Kind, (String, Styles)>,
+}
+
+impl LineAssembler {
+    /// Lines of stream `kind` completed by `content`, with the highlight
+    /// groups of their byte ranges, `styles` being those of `content`.
+    pub fn push(
+        &mut self,
+        kind: Kind,
+        content: &str,
+        styles: &[(Range<usize>, String)],
+    ) -> Vec<(String, Styles)> {
+        let (mut text, mut text_styles) = self.partial.remove(&kind).unwrap_or_default();
+        let offset = text.len();
+        text.push_str(content);
+        text_styles.extend(
+            styles
+                .iter()
+                .map(|(r, group)| (r.start + offset..r.end + offset, group.to_string())),
+        );
+
+        let mut lines = vec![];
+        let mut start = 0;
+        while let Some(i) = text[start..].find('\n') {
+            let end = start + i;
+            let line = text[start..end].trim_end_matches('\r');
+            let line_styles = styles_of(&text_styles, start..start + line.len());
+            lines.push((line.to_string(), line_styles));
+            start = end + 1;
+        }
+        if start < text.len() {
+            let rest_styles = styles_of(&text_styles, start..text.len());
+            self.partial
+                .insert(kind, (text[start..].to_string(), rest_styles));
+        }
+
+        lines
+    }
+
+    /// The incomplete lines when the output ends.
+    pub fn end(&mut self) -> Vec<(Kind, String, Styles)> {
+        let mut lines = vec![];
+        for kind in &[Kind::Out, Kind::Err] {
+            if let Some((text, styles)) = self.partial.remove(kind) {
+                lines.push((*kind, text.trim_end_matches('\r').to_string(), styles));
+            }
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: Vec<(String, Styles)>) -> Vec<String> {
+        lines.into_iter().map(|(line, _)| line).collect()
+    }
+
+    #[test]
+    fn chunks_are_joined_into_lines() {
+        let mut lines = LineAssembler::default();
+        assert!(lines.push(Kind::Out, "a", &[]).is_empty());
+        assert_eq!(texts(lines.push(Kind::Out, "b\nc", &[])), vec!["ab"]);
+        assert_eq!(
+            texts(lines.push(Kind::Out, "\r\n\nd\n", &[])),
+            vec!["c", "", "d"]
+        );
+        assert!(lines.end().is_empty());
+    }
+
+    #[test]
+    fn streams_are_kept_apart() {
+        let mut lines = LineAssembler::default();
+        assert!(lines.push(Kind::Out, "progress: ", &[]).is_empty());
+        assert_eq!(
+            texts(lines.push(Kind::Err, "warning\nmore", &[])),
+            vec!["warning"]
+        );
+        assert_eq!(
+            texts(lines.push(Kind::Out, "done\n", &[])),
+            vec!["progress: done"]
+        );
+        assert_eq!(lines.end(), vec![(Kind::Err, "more".to_string(), vec![])]);
+    }
+
+    #[test]
+    fn styles_follow_their_lines() {
+        let mut lines = LineAssembler::default();
+        let red = "RedBushAnsi_fg1".to_string();
+        assert!(lines
+            .push(Kind::Out, "ab", &[(1..2, red.clone())])
+            .is_empty());
+        assert_eq!(
+            lines.push(Kind::Out, "c\nde\n", &[(0..3, red.clone())]),
+            vec![
+                (
+                    "abc".to_string(),
+                    vec![(1..2, red.clone()), (2..3, red.clone())]
+                ),
+                ("de".to_string(), vec![(0..1, red.clone())]),
+            ]
+        );
+    }
+}
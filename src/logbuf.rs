@@ -3,19 +3,102 @@ use crate::Result;
 use chrono::Local;
 use neovim_lib::neovim_api::Buffer;
 use neovim_lib::{Neovim, NeovimApi};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::Instant;
 
 const DATEFMT: &str = "%H:%M:%S %b %d %Y";
+const TIMEFMT: &str = "%H:%M:%S";
+const NAMESPACE: &str = "redbush";
+/// Namespace of the extmarks at the headers of entries, used for folding.
+const ENTRY_NAMESPACE: &str = "redbush_entries";
+/// Priority of the highlights of styled output, above the default priority
+/// of the highlights of the kinds.
+const STYLE_PRIORITY: i64 = 4097;
+
+/// Highlight groups of byte ranges of text.
+pub type Styles = Vec<(Range<usize>, String)>;
+
+/// Kinds of log entries, each highlighted with its own highlight group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Value,
+    Out,
+    Err,
+    Exception,
+    Timing,
+    System,
+}
+
+impl Kind {
+    pub fn hl_group(self) -> &'static str {
+        match self {
+            Kind::Value => "RedBushValue",
+            Kind::Out => "RedBushOut",
+            Kind::Err => "RedBushErr",
+            Kind::Exception => "RedBushException",
+            Kind::Timing => "RedBushTiming",
+            Kind::System => "RedBushSystem",
+        }
+    }
+}
 
 pub struct LogBuf {
     buf: Buffer,
     bufno: i64,
     max_lines: i64,
+    /// id of the extmark namespace of the highlights
+    ns: i64,
+    /// id of the extmark namespace of the entry headers
+    entry_ns: i64,
+    /// 0-based first lines of the entries in the buffer, oldest first
+    entries: VecDeque<i64>,
+    /// header of the newest entry, without its meta data
+    header: String,
+    /// time the newest entry was started
+    started: String,
+    /// lines waiting to be shown, by kind
+    queued: Vec<(Kind, Vec<String>)>,
+    queued_at: Option<Instant>,
+    /// highlight groups of byte ranges of the queued lines, by the index of
+    /// the line
+    styles: Vec<(usize, Range<usize>, String)>,
+    /// length of the last line shown
+    last_len: usize,
+    /// text replacing the end of the last line shown from a byte column on
+    /// the next `flush`
+    last_end: Option<(usize, String)>,
+    /// line count of the buffer and id of its window, as last seen. Both are
+    /// refreshed at the start of each entry.
+    line_cnt: Option<i64>,
+    winid: Option<i64>,
+    /// NVIM variable holding the id of the window showing the buffer
+    winid_var: String,
+}
+
+fn create_namespace(nvim: &mut Neovim, name: &str) -> Result<i64> {
+    nvim.call_function("nvim_create_namespace", vec![name.into()])?
+        .as_i64()
+        .ok_or_else(|| MyError::from("Unable to create extmark namespace"))
 }
 
 impl LogBuf {
     pub fn new(nvim: &mut Neovim, max_lines: i64, path: &str) -> Result<Self> {
+        LogBuf::with_window(nvim, max_lines, path, "logbuf_winid")
+    }
+
+    /// LogBuf for buffer `path` shown in the window `g:{winid_var}`.
+    pub fn with_window(
+        nvim: &mut Neovim,
+        max_lines: i64,
+        path: &str,
+        winid_var: &str,
+    ) -> Result<Self> {
         log::debug!("Creating LogBuf: max_lines={}, path={}", max_lines, path);
 
+        let ns = create_namespace(nvim, NAMESPACE)?;
+        let entry_ns = create_namespace(nvim, ENTRY_NAMESPACE)?;
+
         let buffers = nvim.list_bufs()?;
         match buffers
             .into_iter()
@@ -25,47 +108,162 @@ impl LogBuf {
                 bufno: buf.get_number(nvim)?,
                 buf,
                 max_lines,
+                ns,
+                entry_ns,
+                entries: VecDeque::new(),
+                header: String::new(),
+                started: String::new(),
+                queued: vec![],
+                queued_at: None,
+                styles: vec![],
+                last_len: 0,
+                last_end: None,
+                line_cnt: None,
+                winid: None,
+                winid_var: winid_var.to_string(),
             }),
             None => Err(MyError::from("Logbuf not opened in NVIM")),
         }
     }
 
+    pub fn max_lines(&self) -> i64 {
+        self.max_lines
+    }
+
+    /// Shows `msg` as an entry of its own.
     pub fn message(&mut self, nvim: &mut Neovim, msg: &str) -> Result<()> {
-        log::debug!("Showing logbuf welcome");
-        let lines = vec![format!(";; [{}] {}", Local::now().format(DATEFMT), msg)];
-        if self.buf.line_count(nvim)? > 1 {
-            self.append_lines(nvim, lines)?;
-        } else {
-            self.buf.set_lines(nvim, 0, -1, true, lines)?;
+        log::debug!("Showing logbuf message: {}", msg);
+        self.flush(nvim)?;
+        self.header = format!(";; [{}] {}", Local::now().format(DATEFMT), msg);
+        let line = self.header_line("");
+        self.write(nvim, vec![(Kind::System, vec![line])], vec![], true)
+    }
+
+    /// Starts a new entry for the evaluation of `form` in `ns`. Lines shown
+    /// after it are nested in the entry.
+    pub fn entry(&mut self, nvim: &mut Neovim, form: &str, ns: &str) -> Result<()> {
+        self.flush(nvim)?;
+        self.line_cnt = None;
+        self.winid = None;
+        self.header = form.to_string();
+        self.started = Local::now().format(TIMEFMT).to_string();
+        let line = self.header_line(&self.meta(ns, None));
+        self.write(nvim, vec![(Kind::System, vec![line])], vec![], true)
+    }
+
+    /// Completes the header of the newest entry with the namespace `ns` and
+    /// the duration `ms` of the evaluation.
+    pub fn finish_entry(&mut self, nvim: &mut Neovim, ns: &str, ms: usize) -> Result<()> {
+        let start = match self.entries.back() {
+            Some(start) => *start,
+            None => return Ok(()),
         };
 
+        let line = self.header_line(&self.meta(ns, Some(ms)));
+        let mut atom: Vec<neovim_lib::Value> = vec![
+            self.clearer(self.ns, start, start + 1).into(),
+            self.clearer(self.entry_ns, start, start + 1).into(),
+            self.setter(start, start + 1, vec![line.clone()]).into(),
+            self.marker(start).into(),
+        ];
+        atom.append(&mut self.header_highlighters(start, &line));
+        nvim.call_atomic(atom)?;
+
         Ok(())
     }
 
-    fn trimmer(&mut self, trim_cnt: i64) -> Vec<neovim_lib::Value> {
-        let mut trim: Vec<neovim_lib::Value> = vec!["nvim_buf_set_lines".into()];
-        let args: Vec<neovim_lib::Value> = vec![
-            self.bufno.into(),
-            0.into(),
-            trim_cnt.into(),
-            true.into(),
-            neovim_lib::Value::Array(vec![]),
-        ];
+    fn meta(&self, ns: &str, ms: Option<usize>) -> String {
+        let meta = match ns {
+            "" => format!("; {}", self.started),
+            ns => format!("; {} {}", ns, self.started),
+        };
 
-        trim.push(args.into());
+        match ms {
+            Some(ms) => format!("{} ({} ms)", meta, ms),
+            None => meta,
+        }
+    }
 
-        trim
+    fn header_line(&self, meta: &str) -> String {
+        match meta {
+            "" => self.header.to_string(),
+            meta => format!("{}  {}", self.header, meta),
+        }
     }
 
-    fn appender(&mut self, lines: Vec<String>) -> Vec<neovim_lib::Value> {
-        let mut append: Vec<neovim_lib::Value> = vec!["nvim_buf_set_lines".into()];
+    fn header_highlighters(&mut self, line: i64, text: &str) -> Vec<neovim_lib::Value> {
+        let header_len = self.header.len().min(text.len());
+        let mut highlights = vec![self
+            .highlighter((line, 0), (line, header_len), Kind::System)
+            .into()];
+        if text.len() > header_len {
+            highlights.push(
+                self.highlighter((line, header_len), (line, text.len()), Kind::Timing)
+                    .into(),
+            );
+        }
+
+        highlights
+    }
+
+    /// Removes lines `start` to `end` (exclusive) with their extmarks.
+    fn trimmer(&mut self, start: i64, end: i64) -> Vec<neovim_lib::Value> {
+        vec![
+            self.clearer(self.ns, start, end).into(),
+            self.clearer(self.entry_ns, start, end).into(),
+            self.setter(start, end, vec![]).into(),
+        ]
+    }
+
+    fn setter(&mut self, start: i64, end: i64, lines: Vec<String>) -> Vec<neovim_lib::Value> {
+        let mut set: Vec<neovim_lib::Value> = vec!["nvim_buf_set_lines".into()];
         let mut args: Vec<neovim_lib::Value> =
-            vec![self.bufno.into(), (-1).into(), (-1).into(), true.into()];
+            vec![self.bufno.into(), start.into(), end.into(), true.into()];
         let lns: Vec<neovim_lib::Value> = lines.into_iter().map(|l| l.into()).collect();
         args.push(lns.into());
-        append.push(args.into());
+        set.push(args.into());
 
-        append
+        set
+    }
+
+    /// Replaces the byte range `cols` of `line` with `text`.
+    fn replacer(&mut self, line: i64, cols: Range<usize>, text: &str) -> Vec<neovim_lib::Value> {
+        let mut replace: Vec<neovim_lib::Value> = vec!["nvim_buf_set_text".into()];
+        let args: Vec<neovim_lib::Value> = vec![
+            self.bufno.into(),
+            line.into(),
+            (cols.start as i64).into(),
+            line.into(),
+            (cols.end as i64).into(),
+            neovim_lib::Value::Array(vec![text.into()]),
+        ];
+        replace.push(args.into());
+
+        replace
+    }
+
+    fn clearer(&mut self, ns: i64, start: i64, end: i64) -> Vec<neovim_lib::Value> {
+        let mut clear: Vec<neovim_lib::Value> = vec!["nvim_buf_clear_namespace".into()];
+        let args: Vec<neovim_lib::Value> =
+            vec![self.bufno.into(), ns.into(), start.into(), end.into()];
+        clear.push(args.into());
+
+        clear
+    }
+
+    /// Extmark marking `line` as the header of an entry.
+    fn marker(&mut self, line: i64) -> Vec<neovim_lib::Value> {
+        let mut mark: Vec<neovim_lib::Value> = vec!["nvim_buf_set_extmark".into()];
+        let args: Vec<neovim_lib::Value> = vec![
+            self.bufno.into(),
+            self.entry_ns.into(),
+            line.into(),
+            0.into(),
+            neovim_lib::Value::Map(vec![]),
+        ];
+        mark.push(args.into());
+
+        mark
     }
 
     fn cursor_setter(
@@ -73,62 +271,289 @@ impl LogBuf {
         nvim: &mut Neovim,
         cursor_line: i64,
     ) -> Option<Vec<neovim_lib::Value>> {
-        match nvim.get_var("logbuf_winid") {
-            Ok(id) => match id.as_i64() {
-                Some(i) => {
-                    let mut cursor: Vec<neovim_lib::Value> = vec!["nvim_win_set_cursor".into()];
-                    let mut args: Vec<neovim_lib::Value> = vec![i.into()];
-                    let tuple: Vec<neovim_lib::Value> = vec![cursor_line.into(), 0i64.into()];
-                    args.push(tuple.into());
-                    cursor.push(args.into());
-
-                    Some(cursor)
+        if self.winid.is_none() {
+            self.winid = match nvim.get_var(&self.winid_var) {
+                Ok(id) => id.as_i64(),
+                Err(_) => {
+                    log::debug!("Unable to get 'g:{}' variable from NVIM", self.winid_var);
+                    None
                 }
-                None => None,
+            };
+        }
+
+        self.winid.map(|i| {
+            let mut cursor: Vec<neovim_lib::Value> = vec!["nvim_win_set_cursor".into()];
+            let mut args: Vec<neovim_lib::Value> = vec![i.into()];
+            let tuple: Vec<neovim_lib::Value> = vec![cursor_line.into(), 0i64.into()];
+            args.push(tuple.into());
+            cursor.push(args.into());
+
+            cursor
+        })
+    }
+
+    /// Extmark highlighting from `start` to `end`, given as 0-based lines
+    /// and byte columns, as `kind`.
+    fn highlighter(
+        &mut self,
+        start: (i64, usize),
+        end: (i64, usize),
+        kind: Kind,
+    ) -> Vec<neovim_lib::Value> {
+        let mut highlight: Vec<neovim_lib::Value> = vec!["nvim_buf_set_extmark".into()];
+        let opts: Vec<(neovim_lib::Value, neovim_lib::Value)> = vec![
+            ("end_row".into(), end.0.into()),
+            ("end_col".into(), (end.1 as i64).into()),
+            ("hl_group".into(), kind.hl_group().into()),
+            ("hl_eol".into(), true.into()),
+        ];
+        let args: Vec<neovim_lib::Value> = vec![
+            self.bufno.into(),
+            self.ns.into(),
+            start.0.into(),
+            (start.1 as i64).into(),
+            neovim_lib::Value::Map(opts),
+        ];
+        highlight.push(args.into());
+
+        highlight
+    }
+
+    /// Extmark highlighting the byte range `cols` of `line` with `hl_group`,
+    /// over the highlight of the kind of the line.
+    fn styler(&mut self, line: i64, cols: Range<usize>, hl_group: &str) -> Vec<neovim_lib::Value> {
+        let mut style: Vec<neovim_lib::Value> = vec!["nvim_buf_set_extmark".into()];
+        let opts: Vec<(neovim_lib::Value, neovim_lib::Value)> = vec![
+            ("end_row".into(), line.into()),
+            ("end_col".into(), (cols.end as i64).into()),
+            ("hl_group".into(), hl_group.into()),
+            ("priority".into(), STYLE_PRIORITY.into()),
+        ];
+        let args: Vec<neovim_lib::Value> = vec![
+            self.bufno.into(),
+            self.ns.into(),
+            line.into(),
+            (cols.start as i64).into(),
+            neovim_lib::Value::Map(opts),
+        ];
+        style.push(args.into());
+
+        style
+    }
+
+    /// Range of lines to remove so that `new_cnt` more lines fit in the
+    /// buffer of `line_cnt` lines. Whole entries are removed, oldest first.
+    /// When that is not enough for more lines of the newest entry, its own
+    /// lines are removed from below its header.
+    fn trim_range(&self, line_cnt: i64, new_cnt: i64, new_entry: bool) -> Option<(i64, i64)> {
+        let excess = line_cnt + new_cnt - self.max_lines;
+        if excess <= 0 {
+            return None;
+        }
+
+        match self.entries.iter().find(|start| **start >= excess) {
+            Some(start) => Some((0, *start)),
+            None if new_entry => Some((0, line_cnt)),
+            None => match self.entries.back() {
+                // all older entries plus lines below the header of the newest
+                Some(_) => Some((0, (excess + 1).min(line_cnt))),
+                None => Some((0, excess.min(line_cnt))),
             },
-            Err(_) => {
-                log::debug!("Unable to get 'g:logbuf_winid' variable from NVIM");
-                None
-            }
         }
     }
 
-    pub fn append_lines(&mut self, nvim: &mut Neovim, lines: Vec<String>) -> Result<()> {
-        let lines_cnt = lines.len() as i64;
+    /// Appends the lines of `chunks`, each highlighted as its kind and the
+    /// ranges of `styles` with their highlight groups, by the index of the
+    /// line. The first line starts a new entry if `new_entry`.
+    fn write(
+        &mut self,
+        nvim: &mut Neovim,
+        chunks: Vec<(Kind, Vec<String>)>,
+        styles: Vec<(usize, Range<usize>, String)>,
+        new_entry: bool,
+    ) -> Result<()> {
+        let lines_cnt = chunks.iter().map(|(_, lines)| lines.len() as i64).sum();
         log::debug!("Appending to NVIM log buffer: {} lines", lines_cnt);
-        let mut cursor_line = self.buf.line_count(nvim)?;
+        let last_end = self.last_end.take();
+        if lines_cnt == 0 && last_end.is_none() {
+            return Ok(());
+        }
 
+        let mut line_cnt = match self.line_cnt {
+            Some(cnt) => cnt,
+            None => self.buf.line_count(nvim)?,
+        };
         let mut atom: Vec<neovim_lib::Value> = vec![];
-        if !lines.is_empty() {
-            if cursor_line + lines_cnt > self.max_lines {
-                let trim_cnt = self.max_lines / 2;
 
-                atom.push(self.trimmer(trim_cnt).into());
+        // changed end of the last line, before it can be trimmed
+        if let Some((col, text)) = last_end.filter(|_| line_cnt > 0) {
+            let col = col.min(self.last_len);
+            atom.push(
+                self.replacer(line_cnt - 1, col..self.last_len, &text)
+                    .into(),
+            );
+            self.last_len = col + text.len();
+        }
+
+        // a new buffer has a single empty line to replace
+        let fresh = self.entries.is_empty() && line_cnt <= 1;
+        if fresh {
+            line_cnt = 0;
+        }
+
+        if let Some((start, end)) = self.trim_range(line_cnt, lines_cnt, new_entry) {
+            match self.entries.back() {
+                // the newest entry itself is cut from below its header
+                Some(newest) if end > *newest + 1 && !new_entry => {
+                    let newest = *newest;
+                    atom.append(&mut self.trimmer(newest + 1, end));
+                    if newest > start {
+                        atom.append(&mut self.trimmer(start, newest));
+                    }
+                    self.entries.clear();
+                    self.entries.push_back(start);
+                    line_cnt -= end - start - 1;
+                }
+                _ => {
+                    atom.append(&mut self.trimmer(start, end));
+                    self.entries.retain(|s| *s >= end);
+                    self.entries.iter_mut().for_each(|s| *s -= end - start);
+                    line_cnt -= end - start;
+                }
+            }
+        }
 
-                cursor_line = self.max_lines - trim_cnt + lines_cnt;
-            } else {
-                cursor_line += lines_cnt;
+        let mut highlights: Vec<neovim_lib::Value> = vec![];
+        for (i, range, group) in styles {
+            highlights.push(self.styler(line_cnt + i as i64, range, &group).into());
+        }
+        let mut lines: Vec<String> = vec![];
+        let mut row = line_cnt;
+        for (kind, mut chunk) in chunks {
+            let mut start = row;
+            if new_entry && row == line_cnt {
+                self.entries.push_back(row);
+                highlights.push(self.marker(row).into());
+                highlights.append(&mut self.header_highlighters(row, &chunk[0]));
+                start += 1;
+            }
+            row += chunk.len() as i64;
+            if let Some(last) = chunk.last() {
+                if start < row {
+                    highlights.push(
+                        self.highlighter((start, 0), (row - 1, last.len()), kind)
+                            .into(),
+                    );
+                }
             }
+            lines.append(&mut chunk);
+        }
 
-            atom.push(self.appender(lines).into());
-            if let Some(cursor) = self.cursor_setter(nvim, cursor_line) {
-                atom.push(cursor.into());
+        if let Some(last) = lines.last() {
+            self.last_len = last.len();
+            match fresh {
+                true => atom.push(self.setter(0, -1, lines).into()),
+                false => atom.push(self.setter(-1, -1, lines).into()),
             }
-            nvim.call_atomic(atom)?;
+        }
+        atom.append(&mut highlights);
+        self.line_cnt = Some(row);
+
+        // the cursor follows the start of the newest entry
+        let cursor_line = match self.entries.back() {
+            Some(start) => start + 1,
+            None => row,
+        };
+        if let Some(cursor) = self.cursor_setter(nvim, cursor_line) {
+            atom.push(cursor.into());
+        }
+
+        let result = nvim.call_atomic(atom)?;
+        if let Some(err) = result.get(1).filter(|err| !err.is_nil()) {
+            log::debug!("Failed to update log buffer: {}", err);
+            self.line_cnt = None;
+            self.winid = None;
         }
 
         Ok(())
     }
 
-    pub fn show(&mut self, nvim: &mut Neovim, prefix: &str, content: &str) -> Result<()> {
-        self.append_lines(
-            nvim,
-            content
-                .lines()
-                .map(|s| format!("{}{}", prefix, s))
-                .collect(),
-        )?;
+    /// Queues `content` with each line prefixed with `prefix` to be shown in
+    /// the newest entry on the next `flush`.
+    pub fn queue(&mut self, kind: Kind, prefix: &str, content: &str) {
+        let lines = content
+            .lines()
+            .map(|s| format!("{}{}", prefix, s))
+            .collect();
+        self.queue_lines(kind, lines);
+    }
 
-        Ok(())
+    fn queue_lines(&mut self, kind: Kind, lines: Vec<String>) {
+        match self.queued.last_mut() {
+            Some((k, queued)) if *k == kind => queued.extend(lines),
+            _ => self.queued.push((kind, lines)),
+        }
+        self.queued_at.get_or_insert_with(Instant::now);
+    }
+
+    /// Queues the whole output line `line` of stream `kind`, prefixed with
+    /// `prefix`. `styles` are highlight groups of byte ranges of `line`.
+    pub fn output_line(&mut self, kind: Kind, prefix: &str, line: &str, styles: Styles) {
+        let index = self.queued_lines();
+        for (range, group) in styles {
+            let range = range.start + prefix.len()..range.end + prefix.len();
+            self.styles.push((index, range, group));
+        }
+        self.queue_lines(kind, vec![format!("{}{}", prefix, line)]);
+    }
+
+    /// Replaces the end of the last line from byte `col` on with `text`,
+    /// e.g. to update the repeat count of a collapsed line.
+    pub fn replace_end(&mut self, col: usize, text: &str) {
+        match self
+            .queued
+            .last_mut()
+            .and_then(|(_, lines)| lines.last_mut())
+        {
+            Some(line) => {
+                line.truncate(col.min(line.len()));
+                line.push_str(text);
+            }
+            None => {
+                self.last_end = Some((col, text.to_string()));
+                self.queued_at.get_or_insert_with(Instant::now);
+            }
+        }
+    }
+
+    /// Number of queued lines.
+    pub fn queued_lines(&self) -> usize {
+        self.queued.iter().map(|(_, lines)| lines.len()).sum()
+    }
+
+    /// When the oldest queued lines were queued, if there are any.
+    pub fn queued_at(&self) -> Option<Instant> {
+        self.queued_at
+    }
+
+    /// Shows the queued lines.
+    pub fn flush(&mut self, nvim: &mut Neovim) -> Result<()> {
+        self.queued_at = None;
+        let queued = std::mem::take(&mut self.queued);
+        let styles = std::mem::take(&mut self.styles);
+        self.write(nvim, queued, styles, false)
+    }
+
+    /// Shows `content` with each line prefixed with `prefix`, nested in the
+    /// newest entry.
+    pub fn show(
+        &mut self,
+        nvim: &mut Neovim,
+        kind: Kind,
+        prefix: &str,
+        content: &str,
+    ) -> Result<()> {
+        self.queue(kind, prefix, content);
+        self.flush(nvim)
     }
 }
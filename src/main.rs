@@ -5,14 +5,28 @@ use neovim_lib::{Neovim, NeovimApi, Session};
 use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 mod error;
 use error::MyError;
 
+mod ansi;
+mod clojure;
+mod diagnostics;
+mod edn;
+mod exception;
+mod explain;
+mod filter;
+mod lines;
 mod logbuf;
 mod nrepl;
 mod prepl;
+mod quickfix;
 mod repl;
+use exception::ExceptionInfo;
 use repl::{ReplReceiver, ReplSender};
 
 type Result<T> = std::result::Result<T, MyError>;
@@ -147,22 +161,628 @@ fn to_params(nvim_args: Vec<neovim_lib::Value>) -> Result<HashMap<repl::Param, r
     Err("Unable to convert NVIM message".into())
 }
 
-fn repl_loop(mut receiver: impl ReplReceiver, logbuf: &mut logbuf::LogBuf) -> Result<()> {
+fn int_param(params: &HashMap<repl::Param, repl::Param>, key: &str) -> Result<usize> {
+    match params.get(&repl::Param::from(key)) {
+        Some(repl::Param::Int(i)) if *i > 0 => Ok(*i as usize),
+        _ => Err(MyError::from(format!(
+            "Missing or invalid '{}' parameter",
+            key
+        ))),
+    }
+}
+
+/// Params of an eval done by redbush itself for `purpose`, e.g. to look up
+/// sources. The result of `code` is printed rather than returned, so that
+/// the `*1`, `*2` and `*3` history of the session is kept. With `tooling`
+/// it is evaluated on the tooling session when the REPL has one.
+fn helper_params(code: &str, purpose: &str, tooling: bool) -> HashMap<repl::Param, repl::Param> {
+    let code = format!(
+        "(clojure.core/let [h1 *1 h2 *2 h3 *3] \
+           (clojure.core/prn {}) \
+           (set! *2 h3) \
+           (set! *1 h2) \
+           h1)",
+        code
+    );
+
+    let mut params = HashMap::new();
+    params.insert(repl::Param::from("code"), repl::Param::Str(code));
+    params.insert(repl::Param::from("purpose"), repl::Param::from(purpose));
+    if tooling {
+        params.insert(repl::Param::from("tooling"), repl::Param::from("true"));
+    }
+
+    params
+}
+
+/// Finds the form to evaluate from the buffer contents and cursor position
+/// sent by NVIM and sets it as the code to evaluate.
+fn locate_form(params: &mut HashMap<repl::Param, repl::Param>) -> Result<()> {
+    let buffer = match params.get(&repl::Param::from("buffer")) {
+        Some(repl::Param::Str(s)) => s.to_string(),
+        _ => return Err(MyError::from("Missing 'buffer' parameter")),
+    };
+    let offset = clojure::offset(
+        &buffer,
+        int_param(params, "cursor-line")?,
+        int_param(params, "cursor-column")?,
+    );
+
+    let range = match params.remove(&repl::Param::from("scope")) {
+        Some(repl::Param::Str(s)) if s == "top" => clojure::top_level_form_at(&buffer, offset),
+        Some(repl::Param::Str(s)) if s == "before" => clojure::form_before(&buffer, offset),
+        _ => clojure::form_at(&buffer, offset),
+    }
+    .ok_or_else(|| MyError::from("No form found at cursor"))?;
+
+    // a wrapped form keeps its position, the wrapper is put before it on
+    // its line or, when it does not fit there, on the line before
+    let (line, column) = clojure::position(&buffer, range.start);
+    let form = &buffer[range];
+    let (line, column, code) = match params.remove(&repl::Param::from("wrap")) {
+        Some(repl::Param::Str(wrap)) => {
+            let prefix = format!("({} ", wrap);
+            let width = prefix.chars().count();
+            if column > width {
+                (line, column - width, format!("{}{})", prefix, form))
+            } else if line > 1 {
+                let padding = " ".repeat(column - 1);
+                (line - 1, 1, format!("({}\n{}{})", wrap, padding, form))
+            } else {
+                (line, 1, format!("{}{})", prefix, form))
+            }
+        }
+        _ => (line, column, form.to_string()),
+    };
+
+    params.remove(&repl::Param::from("cursor-line"));
+    params.remove(&repl::Param::from("cursor-column"));
+    params.insert(repl::Param::from("code"), repl::Param::Str(code));
+    params.insert(repl::Param::from("line"), repl::Param::Int(line as i32));
+    params.insert(repl::Param::from("column"), repl::Param::Int(column as i32));
+
+    Ok(())
+}
+
+/// Splits the code to evaluate into its top-level forms, each positioned at
+/// its own line and column.
+fn split_forms(
+    params: HashMap<repl::Param, repl::Param>,
+) -> Vec<HashMap<repl::Param, repl::Param>> {
+    let code = match params.get(&repl::Param::from("code")) {
+        Some(repl::Param::Str(s)) => s.to_string(),
+        _ => return vec![params],
+    };
+    let line = match params.get(&repl::Param::from("line")) {
+        Some(repl::Param::Int(i)) => *i,
+        _ => 1,
+    };
+    let column = match params.get(&repl::Param::from("column")) {
+        Some(repl::Param::Int(i)) => *i,
+        _ => 1,
+    };
+
+    clojure::read_forms(&code)
+        .iter()
+        .filter(|form| form.kind != clojure::Kind::Discard)
+        .map(|form| {
+            let (l, c) = clojure::position(&code, form.start);
+            let mut params = params.clone();
+            params.insert(
+                repl::Param::from("code"),
+                repl::Param::from(form.text(&code)),
+            );
+            params.insert(
+                repl::Param::from("line"),
+                repl::Param::Int(line + l as i32 - 1),
+            );
+            params.insert(
+                repl::Param::from("column"),
+                repl::Param::Int(if l == 1 {
+                    column + c as i32 - 1
+                } else {
+                    c as i32
+                }),
+            );
+            params
+        })
+        .collect()
+}
+
+/// First line of evaluated code, shown above its results in the log buffer.
+fn echo(code: &str) -> String {
+    let mut lines = code.trim().lines();
+    let first = lines.next().unwrap_or("");
+
+    match lines.next() {
+        Some(_) => format!("{} ...", first),
+        None => first.to_string(),
+    }
+}
+
+/// Replaces the buffer contents sent by NVIM with the namespace declared in it.
+fn resolve_ns(params: &mut HashMap<repl::Param, repl::Param>) {
+    if let Some(repl::Param::Str(buffer)) = params.remove(&repl::Param::from("buffer")) {
+        if let Some(ns) = clojure::ns_name(&buffer) {
+            log::debug!("Evaluating in namespace: {}", ns);
+            params.insert(repl::Param::from("ns"), repl::Param::Str(ns));
+        }
+    }
+}
+
+/// Buffer for `tap>` values, see `RedBushShowTaps`.
+const TAPBUF_PATH: &str = "redbush://taps";
+/// Buffer for `out` and `err` when `g:redbush_output_buffer` is set, see
+/// `RedBushShowOutput`.
+const OUTBUF_PATH: &str = "redbush://output";
+/// Width tapped values are pretty-printed to.
+const TAP_WIDTH: usize = 80;
+
+/// Output is shown in the log buffer in batches, at least this often or
+/// when this many lines are waiting.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+const FLUSH_LINES: usize = 1000;
+
+/// Kinds of stack frames to collapse, from `g:redbush_trace_filter`.
+fn trace_filter(nvim: &mut Neovim) -> Vec<exception::FrameKind> {
+    match nvim.get_var("redbush_trace_filter") {
+        Ok(neovim_lib::Value::Array(kinds)) => kinds
+            .iter()
+            .filter_map(|kind| kind.as_str().and_then(exception::FrameKind::from_name))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Whether ANSI escapes in output are highlighted (`g:redbush_ansi` is
+/// "highlight") or only stripped ("strip").
+fn ansi_highlight(nvim: &mut Neovim) -> bool {
+    match nvim.get_var("redbush_ansi") {
+        Ok(mode) => mode.as_str() != Some("strip"),
+        _ => true,
+    }
+}
+
+/// Output filter from `g:redbush_output_filter`, e.g.
+/// `{'out': {'exclude': 'DEBUG'}, 'collapse': 1}`. Invalid patterns are
+/// reported and ignored.
+fn output_filter(nvim: &mut Neovim) -> Result<filter::Filter> {
+    let config = nvim
+        .get_var("redbush_output_filter")
+        .unwrap_or(neovim_lib::Value::Nil);
+    let get = |val: &neovim_lib::Value, key: &str| -> Option<neovim_lib::Value> {
+        val.as_map()?
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v.clone())
+    };
+
+    let mut streams = vec![];
+    for stream in &["out", "err"] {
+        let patterns = get(&config, stream).unwrap_or(neovim_lib::Value::Nil);
+        let pattern = |key| {
+            get(&patterns, key)
+                .and_then(|p| p.as_str().map(String::from))
+                .unwrap_or_default()
+        };
+        match filter::StreamFilter::new(&pattern("include"), &pattern("exclude")) {
+            Ok(filter) => streams.push(filter),
+            Err(e) => {
+                log::warn!("Invalid output filter of '{}': {}", stream, e);
+                nvim.out_write(&format!("Invalid output filter of '{}': {}\n", stream, e))?;
+                streams.push(filter::StreamFilter::default());
+            }
+        }
+    }
+    let collapse = match get(&config, "collapse") {
+        Some(neovim_lib::Value::Boolean(b)) => b,
+        Some(val) => val.as_i64().is_some_and(|i| i != 0),
+        None => true,
+    };
+
+    let err = streams.pop().unwrap_or_default();
+    let out = streams.pop().unwrap_or_default();
+    Ok(filter::Filter::new(out, err, collapse))
+}
+
+/// Size limit of the output buffer from `g:redbush_output_filesize`, if
+/// `out` and `err` are shown in a buffer of their own.
+fn output_buffer(nvim: &mut Neovim) -> Option<i64> {
+    match nvim.get_var("redbush_output_buffer") {
+        Ok(enabled) if enabled.as_i64().unwrap_or(0) != 0 => nvim
+            .get_var("redbush_output_filesize")
+            .ok()
+            .and_then(|size| size.as_i64()),
+        _ => None,
+    }
+}
+
+/// Buffer `out` and `err` are shown in.
+fn output_buf<'a>(
+    logbuf: &'a mut logbuf::LogBuf,
+    outbuf: &'a mut Option<logbuf::LogBuf>,
+) -> &'a mut logbuf::LogBuf {
+    match outbuf {
+        Some(outbuf) => outbuf,
+        None => logbuf,
+    }
+}
+
+/// Shows the whole output line `line` of stream `kind`, through `filter`
+/// when filtering.
+fn show_line(
+    logbuf: &mut logbuf::LogBuf,
+    filter: Option<&mut filter::Filter>,
+    kind: logbuf::Kind,
+    prefix: &str,
+    line: &str,
+    styles: logbuf::Styles,
+) {
+    let shown = match filter {
+        Some(filter) => filter.line(kind, line, styles),
+        None => Some(filter::Shown::Line(line.to_string(), styles)),
+    };
+    match shown {
+        Some(filter::Shown::Line(line, styles)) => logbuf.output_line(kind, prefix, &line, styles),
+        Some(filter::Shown::Repeat { len, count }) => {
+            logbuf.replace_end(prefix.len() + len, &filter::repeat_suffix(count))
+        }
+        None => (),
+    }
+}
+
+/// Source roots from `g:redbush_source_paths`, relative to the working
+/// directory of NVIM.
+fn source_roots(nvim: &mut Neovim) -> Result<Vec<PathBuf>> {
+    let cwd = nvim.call_function("getcwd", vec![])?;
+    let paths = match nvim.get_var("redbush_source_paths") {
+        Ok(neovim_lib::Value::Array(paths)) => paths,
+        _ => vec![],
+    };
+    let paths: Vec<&str> = paths.iter().filter_map(|p| p.as_str()).collect();
+
+    Ok(quickfix::roots(cwd.as_str().unwrap_or("."), &paths))
+}
+
+/// Sets the quickfix list to the frames of `info`, `urls` being the result
+/// of the resource lookup for them.
+fn set_quickfix(nvim: &mut Neovim, info: &ExceptionInfo, urls: &str) -> Result<()> {
+    let roots = source_roots(nvim)?;
+    let resources = quickfix::unresolved(info, &roots);
+    let urls = edn::parse(urls).unwrap_or(edn::Value::Nil);
+    let items = quickfix::items(info, &roots, &resources, &urls);
+
+    let what = vec![
+        ("title".into(), info.summary().into()),
+        ("items".into(), items.into()),
+    ];
+    nvim.call_function(
+        "setqflist",
+        vec![
+            neovim_lib::Value::Array(vec![]),
+            " ".into(),
+            neovim_lib::Value::Map(what),
+        ],
+    )?;
+    nvim.command("copen")?;
+
+    Ok(())
+}
+
+/// Opens `file` at `line` in the previous window.
+fn goto_file(nvim: &mut Neovim, file: &str, line: i64) -> Result<()> {
+    nvim.command(&format!(
+        "wincmd p | execute 'edit +{} ' . fnameescape('{}')",
+        line,
+        file.replace('\'', "''")
+    ))?;
+
+    Ok(())
+}
+
+/// Receives REPL replies in a thread of their own, so that queued output
+/// can be shown while waiting for them.
+fn receive_replies(mut receiver: impl ReplReceiver) -> mpsc::Receiver<repl::Result<repl::Reply>> {
+    let (sender, replies) = mpsc::channel();
+    thread::spawn(move || loop {
+        let reply = receiver.receive();
+        let last = matches!(
+            reply,
+            Err(_)
+                | Ok(repl::Reply {
+                    response: repl::Response::Eof(),
+                    ..
+                })
+        );
+        if sender.send(reply).is_err() || last {
+            break;
+        }
+    });
+
+    replies
+}
+
+/// Sets `g:redbush_pending` to the number of evaluations waiting for results.
+fn set_pending(nvim: &mut Neovim, count: usize) -> Result<()> {
+    nvim.set_var("redbush_pending", neovim_lib::Value::from(count as i64))?;
+
+    Ok(())
+}
+
+/// Shows the queued output once enough of it has been waiting.
+fn flush_output(nvim: &mut Neovim, logbuf: &mut logbuf::LogBuf) -> Result<()> {
+    let due = logbuf
+        .queued_at()
+        .is_some_and(|queued_at| queued_at.elapsed() >= FLUSH_INTERVAL);
+    if due || logbuf.queued_lines() >= FLUSH_LINES {
+        logbuf.flush(nvim)?;
+    }
+
+    Ok(())
+}
+
+fn repl_loop(
+    receiver: impl ReplReceiver,
+    logbuf: &mut logbuf::LogBuf,
+    last_exception: Arc<Mutex<Option<ExceptionInfo>>>,
+    browsed_exception: Arc<Mutex<Option<ExceptionInfo>>>,
+    filtering: Arc<AtomicBool>,
+) -> Result<()> {
     log::debug!("repl_loop starting NVIM event loop");
     let mut nvim = connect_nvim_socket()?;
 
     let mut prefix = HashMap::<String, String>::new();
+    prefix.insert("form".into(), ";; ".into());
     prefix.insert("err".into(), ";✖ ".into());
     prefix.insert("exc".into(), ";  ".into());
     prefix.insert("out".into(), ";".into());
-    prefix.insert("ns".into(), ";=> ".into());
     prefix.insert("status".into(), ";; Status: ".into());
     prefix.insert("value".into(), "".into());
 
     logbuf.message(&mut nvim, "Start")?;
 
+    // out and err are shown here instead of the log buffer if configured
+    let mut outbuf = match output_buffer(&mut nvim) {
+        Some(max_lines) => {
+            nvim.call_function("RedBushShowOutput", vec![])?;
+            let mut outbuf = logbuf::LogBuf::with_window(
+                &mut nvim,
+                max_lines,
+                OUTBUF_PATH,
+                "redbush_output_winid",
+            )?;
+            outbuf.message(&mut nvim, "Start")?;
+            Some(outbuf)
+        }
+        None => None,
+    };
+
+    // id of the evaluation whose results are being shown
+    let mut shown_id = "".to_string();
+    // id of the evaluation whose output is being shown in the output buffer
+    let mut output_id = "".to_string();
+    let mut diagnostics = diagnostics::Diagnostics::default();
+    // compiler warnings of the evaluation, shown with its result
+    let mut warnings: Vec<diagnostics::Diagnostic> = vec![];
+    // opened on the first tap
+    let mut tapbuf: Option<logbuf::LogBuf> = None;
+    let mut out_ansi = ansi::Parser::default();
+    let mut err_ansi = ansi::Parser::default();
+    let mut ansi_highlights = ansi::Highlights::new(ansi_highlight(&mut nvim));
+    let mut filter = output_filter(&mut nvim)?;
+    let mut was_filtering = true;
+    let mut lines = lines::LineAssembler::default();
+    // printed result of the helper eval being done, see `helper_params`
+    let mut helper_out = String::new();
+
+    let pending = receiver.pending().clone();
+    let mut pending_cnt = pending.len();
+    let replies = receive_replies(receiver);
+
     loop {
-        match receiver.receive() {
+        let queued_at = logbuf
+            .queued_at()
+            .into_iter()
+            .chain(outbuf.as_ref().and_then(|outbuf| outbuf.queued_at()))
+            .min();
+        let reply = match queued_at {
+            Some(queued_at) => {
+                match replies.recv_timeout(FLUSH_INTERVAL.saturating_sub(queued_at.elapsed())) {
+                    Ok(reply) => reply,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        logbuf.flush(&mut nvim)?;
+                        if let Some(outbuf) = outbuf.as_mut() {
+                            outbuf.flush(&mut nvim)?;
+                        }
+                        continue;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match replies.recv() {
+                Ok(reply) => reply,
+                Err(_) => break,
+            },
+        };
+
+        // evaluations leave pending with their last reply, whatever it is
+        if pending.len() != pending_cnt {
+            pending_cnt = pending.len();
+            set_pending(&mut nvim, pending_cnt)?;
+        }
+
+        // the filters are read again when turned back on
+        let is_filtering = filtering.load(Ordering::Relaxed);
+        if is_filtering && !was_filtering {
+            filter = output_filter(&mut nvim)?;
+        }
+        was_filtering = is_filtering;
+
+        // incomplete escapes and lines end with the evaluation, collapsing
+        // with any other reply and when the filter is turned off
+        let is_output = matches!(
+            &reply,
+            Ok(repl::Reply {
+                response: repl::Response::Out(_) | repl::Response::Err(_),
+                ..
+            })
+        );
+        let ends_output = match &reply {
+            Ok(repl::Reply {
+                eval: Some(eval),
+                response,
+            }) if eval.purpose.is_empty() => match response {
+                repl::Response::Value(..) | repl::Response::Exception(_) => true,
+                repl::Response::Status(v) => v.contains(&"done".to_string()),
+                _ => false,
+            },
+            _ => false,
+        };
+        if ends_output {
+            let mut ended = vec![];
+            for (kind, parser) in &mut [
+                (logbuf::Kind::Out, &mut out_ansi),
+                (logbuf::Kind::Err, &mut err_ansi),
+            ] {
+                let (rest, styles) = parser.end();
+                let styles = ansi_highlights.groups(&mut nvim, styles)?;
+                for (line, styles) in lines.push(*kind, &rest, &styles) {
+                    ended.push((*kind, line, styles));
+                }
+            }
+            ended.extend(lines.end());
+            for (kind, line, styles) in ended {
+                let key = if kind == logbuf::Kind::Err {
+                    warnings.extend(diagnostics::Diagnostic::parse_warnings(&line));
+                    "err"
+                } else {
+                    "out"
+                };
+                let filter = Some(&mut filter).filter(|_| filtering.load(Ordering::Relaxed));
+                show_line(
+                    output_buf(logbuf, &mut outbuf),
+                    filter,
+                    kind,
+                    prefix.get(key).unwrap_or(&"".to_string()),
+                    &line,
+                    styles,
+                );
+            }
+        }
+        if !is_output || !filtering.load(Ordering::Relaxed) {
+            filter.end();
+        }
+
+        if let Ok(repl::Reply {
+            eval: Some(eval), ..
+        }) = &reply
+        {
+            if eval.id != shown_id && eval.purpose.is_empty() {
+                shown_id = eval.id.to_string();
+                logbuf.entry(
+                    &mut nvim,
+                    &format!(
+                        "{}{}",
+                        prefix.get("form").unwrap_or(&"".to_string()),
+                        echo(&eval.code)
+                    ),
+                    &eval.ns,
+                )?;
+            }
+            if let Some(outbuf) = outbuf.as_mut().filter(|_| is_output) {
+                if eval.id != output_id && eval.purpose.is_empty() {
+                    output_id = eval.id.to_string();
+                    outbuf.entry(
+                        &mut nvim,
+                        &format!(
+                            "{}{}",
+                            prefix.get("form").unwrap_or(&"".to_string()),
+                            echo(&eval.code)
+                        ),
+                        &eval.ns,
+                    )?;
+                }
+            }
+        }
+
+        let (purpose, eval_ns, elapsed) = match &reply {
+            Ok(repl::Reply {
+                eval: Some(eval), ..
+            }) => (
+                eval.purpose.to_string(),
+                eval.ns.to_string(),
+                Some(eval.started.elapsed().as_millis() as usize),
+            ),
+            _ => ("".to_string(), "".to_string(), None),
+        };
+        // file and lines of the evaluated code, whose diagnostics a
+        // successful evaluation replaces
+        let evaluated = match &reply {
+            Ok(repl::Reply {
+                eval: Some(eval), ..
+            }) => Some((
+                eval.file.to_string(),
+                match eval.line {
+                    0 => None,
+                    line => Some(line..line + eval.code.lines().count() as i64),
+                },
+            )),
+            _ => None,
+        };
+
+        match reply.map(|r| r.response) {
+            Ok(repl::Response::Out(s)) if !purpose.is_empty() => {
+                helper_out.push_str(&s);
+            }
+            Ok(repl::Response::Value(_, _, _, _)) if purpose == "quickfix" => {
+                let urls = std::mem::take(&mut helper_out);
+                log::debug!("Got quickfix resource URLs from REPL: {}", urls);
+                let info = last_exception.lock().unwrap().clone();
+                if let Some(info) = info {
+                    set_quickfix(&mut nvim, &info, &urls)?;
+                }
+            }
+            Ok(repl::Response::Value(_, _, _, _)) if purpose == "last-exception" => {
+                let value = std::mem::take(&mut helper_out);
+                match edn::parse(&value)
+                    .ok()
+                    .and_then(|val| ExceptionInfo::from_edn(&val))
+                {
+                    Some(info) => {
+                        let (lines, frames) = info.browse();
+                        let lines: Vec<neovim_lib::Value> =
+                            lines.into_iter().map(|l| l.into()).collect();
+                        let frames: Vec<neovim_lib::Value> = frames
+                            .into_iter()
+                            .map(|(line, i)| {
+                                neovim_lib::Value::Array(vec![line.into(), (i as i64).into()])
+                            })
+                            .collect();
+                        *last_exception.lock().unwrap() = Some(info.clone());
+                        *browsed_exception.lock().unwrap() = Some(info);
+                        nvim.call_function(
+                            "RedBushShowException",
+                            vec![lines.into(), frames.into()],
+                        )?;
+                    }
+                    None => nvim.out_write("No exception\n")?,
+                }
+            }
+            Ok(repl::Response::Value(_, _, _, _)) if purpose == "goto-frame" => {
+                let value = std::mem::take(&mut helper_out);
+                let target = match edn::parse(&value) {
+                    Ok(edn::Value::Vector(v)) => v
+                        .first()
+                        .and_then(edn::Value::as_str)
+                        .and_then(quickfix::url_to_file)
+                        .zip(v.get(1).and_then(edn::Value::as_i64)),
+                    _ => None,
+                };
+                match target {
+                    Some((file, line)) => goto_file(&mut nvim, &file, line)?,
+                    None => nvim.out_write("Source not found\n")?,
+                }
+            }
             Ok(repl::Response::Value(value, ns, ms, form)) => {
                 log::debug!(
                     "Got VALUE response from REPL: value: {}, ns: {}, ms: {}, form: {}",
@@ -173,33 +793,123 @@ fn repl_loop(mut receiver: impl ReplReceiver, logbuf: &mut logbuf::LogBuf) -> Re
                 );
                 logbuf.show(
                     &mut nvim,
+                    logbuf::Kind::Value,
                     prefix.get("value").unwrap_or(&"".to_string()),
                     &value,
                 )?;
-                logbuf.show(&mut nvim, prefix.get("ns").unwrap_or(&"".to_string()), &ns)?;
+                logbuf.finish_entry(&mut nvim, &ns, ms)?;
                 nvim.out_write(&format!("{}\n", &value))?;
+                if let Some((file, lines)) = evaluated {
+                    diagnostics.clear(&mut nvim, &file, lines)?;
+                }
+                for warning in warnings.drain(..) {
+                    diagnostics.add(&mut nvim, warning)?;
+                }
             }
             Ok(repl::Response::Err(s)) => {
                 log::debug!("Got ERR response from REPL: {}", s);
-                logbuf.show(&mut nvim, prefix.get("err").unwrap_or(&"".to_string()), &s)?;
-                nvim.out_write(&format!("ERROR: {}\n", &s))?;
+                let (s, styles) = err_ansi.parse(&s);
+                let styles = ansi_highlights.groups(&mut nvim, styles)?;
+                let stream_prefix = prefix.get("err").unwrap_or(&"".to_string()).to_string();
+                let output_buf = output_buf(logbuf, &mut outbuf);
+                let mut filter = Some(&mut filter).filter(|_| filtering.load(Ordering::Relaxed));
+                for (line, styles) in lines.push(logbuf::Kind::Err, &s, &styles) {
+                    warnings.extend(diagnostics::Diagnostic::parse_warnings(&line));
+                    show_line(
+                        output_buf,
+                        filter.as_deref_mut(),
+                        logbuf::Kind::Err,
+                        &stream_prefix,
+                        &line,
+                        styles,
+                    );
+                }
+                flush_output(&mut nvim, output_buf)?;
             }
             Ok(repl::Response::Out(s)) => {
                 log::debug!("Got OUT response from REPL: {}", s);
-                logbuf.show(&mut nvim, prefix.get("out").unwrap_or(&"".to_string()), &s)?;
+                let (s, styles) = out_ansi.parse(&s);
+                let styles = ansi_highlights.groups(&mut nvim, styles)?;
+                let stream_prefix = prefix.get("out").unwrap_or(&"".to_string()).to_string();
+                let output_buf = output_buf(logbuf, &mut outbuf);
+                let mut filter = Some(&mut filter).filter(|_| filtering.load(Ordering::Relaxed));
+                for (line, styles) in lines.push(logbuf::Kind::Out, &s, &styles) {
+                    show_line(
+                        output_buf,
+                        filter.as_deref_mut(),
+                        logbuf::Kind::Out,
+                        &stream_prefix,
+                        &line,
+                        styles,
+                    );
+                }
+                flush_output(&mut nvim, output_buf)?;
+            }
+            Ok(repl::Response::Exception(info)) if !purpose.is_empty() => {
+                helper_out.clear();
+                // failing helper evals keep the exception being looked at
+                log::debug!("Got EXCEPTION response to {} eval: {:?}", purpose, info);
+                let what = match purpose.as_str() {
+                    "quickfix" => "Quickfix lookup",
+                    "last-exception" => "Reading the last exception",
+                    _ => "Source lookup",
+                };
+                nvim.out_write(&format!("{} failed: {}\n", what, info.summary()))?;
             }
-            Ok(repl::Response::Exception(trace, err)) => {
-                log::debug!("Got EXCEPTION response from REPL: {} {}", trace, err);
+            Ok(repl::Response::Exception(info)) => {
+                log::debug!("Got EXCEPTION response from REPL: {:?}", info);
+                let summary = info.summary();
+                let detail = info.detail(&trace_filter(&mut nvim));
                 logbuf.show(
                     &mut nvim,
+                    logbuf::Kind::Exception,
                     prefix.get("err").unwrap_or(&"".to_string()),
-                    &err,
+                    &summary,
                 )?;
                 logbuf.show(
                     &mut nvim,
+                    logbuf::Kind::Exception,
                     prefix.get("exc").unwrap_or(&"".to_string()),
-                    &trace,
+                    &detail,
+                )?;
+                if let Some(ms) = elapsed {
+                    logbuf.finish_entry(&mut nvim, &eval_ns, ms)?;
+                }
+                nvim.out_write(&format!("{}\n", &summary))?;
+                if let Some(diagnostic) = diagnostics::Diagnostic::from_exception(&info) {
+                    diagnostics.add(&mut nvim, diagnostic)?;
+                }
+                *last_exception.lock().unwrap() = Some(info);
+            }
+            Ok(repl::Response::Tap(value)) => {
+                log::debug!("Got TAP response from REPL: {}", value);
+                if tapbuf.is_none() {
+                    nvim.call_function("RedBushShowTaps", vec![])?;
+                    tapbuf = Some(logbuf::LogBuf::with_window(
+                        &mut nvim,
+                        logbuf.max_lines(),
+                        TAPBUF_PATH,
+                        "redbush_tap_winid",
+                    )?);
+                }
+                if let Some(tapbuf) = tapbuf.as_mut() {
+                    let value = match edn::parse(&value) {
+                        Ok(val) => val.pretty(TAP_WIDTH),
+                        Err(_) => value,
+                    };
+                    tapbuf.entry(&mut nvim, ";; tap>", "")?;
+                    tapbuf.show(&mut nvim, logbuf::Kind::Value, "", &value)?;
+                }
+            }
+            Ok(repl::Response::NamespaceNotFound(ns)) => {
+                log::debug!("Got NAMESPACE-NOT-FOUND response from REPL: {}", ns);
+                logbuf.show(
+                    &mut nvim,
+                    logbuf::Kind::Err,
+                    prefix.get("err").unwrap_or(&"".to_string()),
+                    &format!("Namespace not found: {}", ns),
                 )?;
+                nvim.call_function("RedBushNamespaceNotFound", vec![ns.as_str().into()])?;
             }
             Ok(repl::Response::Other(s)) => {
                 log::debug!("Got OTHER response from REPL: {}", s);
@@ -210,17 +920,30 @@ fn repl_loop(mut receiver: impl ReplReceiver, logbuf: &mut logbuf::LogBuf) -> Re
             Ok(repl::Response::Status(v)) => {
                 log::debug!("Got STATUS response from REPL: {:?}", &v);
 
-                let mut status = "".to_string();
-                v.iter().for_each(|s| status.push_str(&format!("{} ", s)));
+                for message in v.iter().filter_map(|s| repl::status_message(s)) {
+                    logbuf.show(
+                        &mut nvim,
+                        logbuf::Kind::System,
+                        prefix.get("status").unwrap_or(&"".to_string()),
+                        message,
+                    )?;
+                    nvim.out_write(&format!("{}\n", message))?;
+                }
+
+                if v.contains(&"need-input".to_string()) {
+                    nvim.call_function("RedBushNeedInput", vec![])?;
+                }
 
-                // 'status' can be shown for nrepl but not for prepl.
-                // For now this is commented out so that the behaviour is the same for both repls.
-                //
-                // logbuf.show(
-                //     &mut nvim,
-                //     prefix.get("status").unwrap_or(&"".to_string()),
-                //     &status,
-                // )?;
+                if v.contains(&"done".to_string()) {
+                    // warnings of failed evaluations are kept with the old ones
+                    for warning in warnings.drain(..) {
+                        diagnostics.add(&mut nvim, warning)?;
+                    }
+                    logbuf.flush(&mut nvim)?;
+                    if let Some(outbuf) = outbuf.as_mut() {
+                        outbuf.flush(&mut nvim)?;
+                    }
+                }
 
                 if v.contains(&"session-closed".to_string()) {
                     break;
@@ -257,7 +980,23 @@ fn run(
     let nvim_channel = nvim.session.start_event_loop_channel();
 
     let mut logbuf = logbuf::LogBuf::new(&mut nvim, filesize, &filepath)?;
-    let nrepl_t = thread::spawn(move || repl_loop(receiver, &mut logbuf));
+    let pending = receiver.pending().clone();
+    let last_exception = Arc::new(Mutex::new(None));
+    let repl_last_exception = last_exception.clone();
+    // exception shown in the exception buffer, whose frames are jumped to
+    let browsed_exception = Arc::new(Mutex::new(None));
+    let repl_browsed_exception = browsed_exception.clone();
+    let filtering = Arc::new(AtomicBool::new(true));
+    let repl_filtering = filtering.clone();
+    let nrepl_t = thread::spawn(move || {
+        repl_loop(
+            receiver,
+            &mut logbuf,
+            repl_last_exception,
+            repl_browsed_exception,
+            repl_filtering,
+        )
+    });
 
     log::debug!("Setting NVIM 'g:redbush_repl_session_id'");
     nvim.set_var(
@@ -270,11 +1009,106 @@ fn run(
 
         match event.as_str() {
             "eval" => {
-                let params = to_params(nvim_args)?;
+                let mut params = to_params(nvim_args)?;
+                resolve_ns(&mut params);
                 log::debug!("EVAL-message from NVIM, params: {:?}", &params);
+                for params in split_forms(params) {
+                    sender.send(repl::Request::Eval(params))?;
+                }
+            }
+
+            "eval-form" => {
+                let mut params = to_params(nvim_args)?;
+                log::debug!("EVAL-FORM-message from NVIM, params: {:?}", &params);
+                match locate_form(&mut params) {
+                    Ok(()) => {
+                        resolve_ns(&mut params);
+                        sender.send(repl::Request::Eval(params))?;
+                    }
+                    Err(e) => {
+                        log::debug!("Unable to locate form: {}", e);
+                        nvim.out_write(&format!("{}\n", e))?;
+                    }
+                }
+            }
+
+            "load-file" => {
+                let params = to_params(nvim_args)?;
+                log::debug!("LOAD-FILE-message from NVIM");
+                sender.send(repl::Request::LoadFile(params))?;
+            }
+
+            "quickfix" => {
+                log::debug!("QUICKFIX-message from NVIM");
+                let info = last_exception.lock().unwrap().clone();
+                match info {
+                    Some(info) => {
+                        let roots = source_roots(&mut nvim)?;
+                        let code = quickfix::lookup_code(&quickfix::unresolved(&info, &roots));
+                        let params = helper_params(&code, "quickfix", true);
+                        sender.send(repl::Request::Eval(params))?;
+                    }
+                    None => nvim.out_write("No exception\n")?,
+                }
+            }
+
+            "last-exception" => {
+                log::debug!("LAST-EXCEPTION-message from NVIM");
+                // *e is of the user session
+                let params = helper_params(
+                    "(clojure.core/some-> *e clojure.core/Throwable->map)",
+                    "last-exception",
+                    false,
+                );
                 sender.send(repl::Request::Eval(params))?;
             }
 
+            "goto-frame" => {
+                let params = to_params(nvim_args)?;
+                log::debug!("GOTO-FRAME-message from NVIM, params: {:?}", &params);
+                // frames are numbered from 1
+                let index = int_param(&params, "frame").ok().map(|i| i - 1);
+                let frame = match (browsed_exception.lock().unwrap().as_ref(), index) {
+                    (Some(info), Some(i)) => info.trace.get(i).cloned(),
+                    _ => None,
+                };
+                let resource = frame.as_ref().and_then(quickfix::resource);
+
+                match (frame, resource) {
+                    (Some(frame), Some(resource)) => {
+                        match quickfix::find(&resource, &source_roots(&mut nvim)?) {
+                            Some(path) => {
+                                goto_file(&mut nvim, &path.to_string_lossy(), frame.line)?
+                            }
+                            None => {
+                                let code = quickfix::goto_code(&resource, frame.line);
+                                let params = helper_params(&code, "goto-frame", true);
+                                sender.send(repl::Request::Eval(params))?;
+                            }
+                        }
+                    }
+                    _ => nvim.out_write("Source not found\n")?,
+                }
+            }
+
+            "stdin" => {
+                let params = to_params(nvim_args)?;
+                log::debug!("STDIN-message from NVIM, params: {:?}", &params);
+                if let Err(e) = sender.send(repl::Request::Stdin(params)) {
+                    log::debug!("Unable to send stdin: {}", e);
+                    nvim.out_write(&format!("{}\n", e))?;
+                }
+            }
+
+            "toggle-filter" => {
+                log::debug!("TOGGLE-FILTER-message from NVIM");
+                let on = !filtering.fetch_xor(true, Ordering::Relaxed);
+                nvim.out_write(&format!(
+                    "Output filters {}\n",
+                    if on { "on" } else { "off" }
+                ))?;
+            }
+
             "interrupt" => {
                 let params = to_params(nvim_args)?;
                 log::debug!("INTERRUPT-message from NVIM, params: {:?}", &params);
@@ -287,6 +1121,9 @@ fn run(
                 break;
             }
         }
+
+        // evaluations sent above are pending until their results arrive
+        set_pending(&mut nvim, pending.len())?;
     }
 
     log::debug!("Waiting for REPL thread");
@@ -1,11 +1,38 @@
+use crate::exception::ExceptionInfo;
 use crate::repl::{
-    parse_exception, Param, ReplError, ReplReceiver, ReplSender, Request, Response, Result,
+    Eval, Param, Pending, ReplError, ReplReceiver, ReplSender, Reply, Request, Response, Result,
 };
 use bencode_rs as bc;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::TcpStream;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Request id of the eval that forwards `tap>` values as its output.
+const TAP_ID: &str = "redbush-tap";
+
+/// Registers a tap that prints the values to the `*out*` of the eval, which
+/// nREPL keeps sending with the id of the eval. The previous tap is removed
+/// so that reconnecting does not duplicate values.
+const TAP_CODE: &str = "(clojure.core/let [ns (clojure.core/create-ns 'redbush.tap) \
+                              old (clojure.core/some-> (clojure.core/ns-resolve ns 'tap) clojure.core/deref) \
+                              f (clojure.core/bound-fn* (clojure.core/fn [v] (clojure.core/println (clojure.core/pr-str v))))] \
+                          (clojure.core/when old (clojure.core/remove-tap old)) \
+                          (clojure.core/intern ns 'tap f) \
+                          (clojure.core/add-tap f))";
+
+/// Request id of the eval that removes the tap when redbush exits.
+const UNTAP_ID: &str = "redbush-untap";
+
+const UNTAP_CODE: &str = "(clojure.core/some-> (clojure.core/find-ns 'redbush.tap) \
+                                               (clojure.core/ns-resolve 'tap) \
+                                               clojure.core/deref \
+                                               clojure.core/remove-tap)";
+
+/// How long to wait for the tap to be removed before closing its session.
+const UNTAP_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct NreplSender {
     #[allow(dead_code)]
@@ -14,7 +41,12 @@ pub struct NreplSender {
     port: String,
     pub session_id: String,
     request_cnt: usize,
+    pending: Pending,
     writer: BufWriter<TcpStream>,
+    /// tooling session forwarding `tap>` values
+    tap_session: Option<String>,
+    /// signalled when the tap has been removed
+    untapped: mpsc::Receiver<()>,
 }
 
 impl NreplSender {
@@ -33,7 +65,13 @@ pub struct NreplReceiver {
     pub session_id: String,
     #[allow(dead_code)]
     request_cnt: usize,
+    pending: Pending,
     reader: BufReader<TcpStream>,
+    /// output of the tap eval not yet returned as taps
+    tap_out: String,
+    untapped: mpsc::Sender<()>,
+    /// replies to return before reading the next message
+    queue: VecDeque<Reply>,
 }
 
 pub fn new_sender_receiver(host: &str, port: &str) -> Result<(impl ReplSender, impl ReplReceiver)> {
@@ -41,13 +79,18 @@ pub fn new_sender_receiver(host: &str, port: &str) -> Result<(impl ReplSender, i
 
     let stream = TcpStream::connect(format!("{}:{}", host, port))?;
     let stream2 = stream.try_clone()?;
+    let pending = Pending::default();
+    let (untapped_tx, untapped) = mpsc::channel();
 
     let mut sender = NreplSender {
         session_id: "".to_string(),
         host: host.to_string(),
         port: port.to_string(),
         request_cnt: 0,
+        pending: pending.clone(),
         writer: BufWriter::new(stream),
+        tap_session: None,
+        untapped,
     };
 
     let mut receiver = NreplReceiver {
@@ -55,44 +98,26 @@ pub fn new_sender_receiver(host: &str, port: &str) -> Result<(impl ReplSender, i
         host: host.to_string(),
         port: port.to_string(),
         request_cnt: 0,
+        pending,
         reader: BufReader::new(stream2),
+        tap_out: "".to_string(),
+        untapped: untapped_tx,
+        queue: VecDeque::new(),
     };
 
     sender.send(Request::NewSession())?;
 
-    match receiver.receive() {
+    match receiver.receive().map(|r| r.response) {
         Ok(Response::NewSession(session_id)) => {
             sender.session_id = session_id.to_string();
             receiver.session_id = session_id;
 
-            sender.send(Request::DisableNsMaps())?;
-            match receiver.receive() {
-                Ok(Response::Value(_, _, _, _)) => match receiver.receive() {
-                    Ok(Response::Status(vec)) => {
-                        if vec.contains(&"done".to_string()) {
-                            Ok((sender, receiver))
-                        } else {
-                            log::debug!("Unable to disable ns-maps");
-                            Err(ReplError::from("Unable to disable ns-maps"))
-                        }
-                    }
-                    Ok(_) => {
-                        log::debug!("Unexpected response when trying to disable ns-maps");
-                        Err(ReplError::Error(
-                            "Unexpected response when trying to disable ns-maps".to_string(),
-                        ))
-                    }
-                    Err(e) => {
-                        log::debug!("Failed to disable ns-maps");
-                        Err(ReplError::Error(e.to_string()))
-                    }
-                },
-
-                Err(_) | Ok(_) => {
-                    log::debug!("Failed to disable ns-maps");
-                    Err(ReplError::Error("Failed to disable ns-maps".to_string()))
-                }
+            match forward_taps(&mut sender, &mut receiver) {
+                Ok(session_id) => sender.tap_session = Some(session_id),
+                Err(e) => log::warn!("Unable to forward tap> values: {}", e),
             }
+
+            Ok((sender, receiver))
         }
         Ok(x) => {
             log::debug!(
@@ -113,6 +138,33 @@ pub fn new_sender_receiver(host: &str, port: &str) -> Result<(impl ReplSender, i
     }
 }
 
+/// Evaluates `TAP_CODE` on a tooling session of its own, so that the taps
+/// do not mix with user evaluations. Returns the id of the session.
+fn forward_taps(sender: &mut NreplSender, receiver: &mut NreplReceiver) -> Result<String> {
+    let mut params = HashMap::new();
+    params.insert(Param::from("op"), Param::from("clone"));
+    sender.write_and_flush(build_bc_value(params).to_bencode().as_bytes())?;
+
+    match receiver.receive()?.response {
+        Response::NewSession(session_id) => {
+            log::debug!("nREPL tooling session: {}", session_id);
+
+            let mut params = HashMap::new();
+            params.insert(Param::from("op"), Param::from("eval"));
+            params.insert(Param::from("code"), Param::from(TAP_CODE));
+            params.insert(Param::from("session"), Param::from(session_id.as_str()));
+            params.insert(Param::from("id"), Param::from(TAP_ID));
+            sender.write_and_flush(build_bc_value(params).to_bencode().as_bytes())?;
+
+            Ok(session_id)
+        }
+        x => Err(ReplError::Error(format!(
+            "Unexpected nREPL response for tooling session: {:?}",
+            x
+        ))),
+    }
+}
+
 fn build_bc_value(hm: HashMap<Param, Param>) -> bc::Value {
     let mut bcmap = HashMap::<bc::Value, bc::Value>::new();
     for (k, v) in hm.iter() {
@@ -129,28 +181,70 @@ fn build_bc_value(hm: HashMap<Param, Param>) -> bc::Value {
     bc::Value::from(bcmap)
 }
 
+impl NreplSender {
+    /// Removes the tap and closes the tooling session forwarding it.
+    fn stop_taps(&mut self) -> Result<()> {
+        let session_id = match self.tap_session.take() {
+            Some(session_id) => session_id,
+            None => return Ok(()),
+        };
+        log::debug!("Closing nREPL tooling session: {}", session_id);
+
+        let mut params = HashMap::new();
+        params.insert(Param::from("op"), Param::from("eval"));
+        params.insert(Param::from("code"), Param::from(UNTAP_CODE));
+        params.insert(Param::from("session"), Param::from(session_id.as_str()));
+        params.insert(Param::from("id"), Param::from(UNTAP_ID));
+        self.write_and_flush(build_bc_value(params).to_bencode().as_bytes())?;
+        // closing the session could cut the eval short
+        if self.untapped.recv_timeout(UNTAP_TIMEOUT).is_err() {
+            log::warn!("Timed out removing the tap");
+        }
+
+        let mut params = HashMap::new();
+        params.insert(Param::from("op"), Param::from("close"));
+        params.insert(Param::from("session"), Param::from(session_id.as_str()));
+        params.insert(Param::from("id"), Param::from(UNTAP_ID));
+        self.write_and_flush(build_bc_value(params).to_bencode().as_bytes())
+    }
+}
+
 impl ReplSender for NreplSender {
     fn send(&mut self, req: Request) -> Result<()> {
+        let mut session = self.session_id.to_string();
         let mut params = match req {
             Request::NewSession() => {
                 let mut params = HashMap::new();
                 params.insert(Param::from("op"), Param::from("clone"));
                 params
             }
-            Request::DisableNsMaps() => {
-                let mut params = HashMap::new();
+            Request::Eval(mut params) => {
+                self.pending
+                    .push(Eval::new(&self.request_cnt.to_string(), &params));
+                // purpose and tooling are for redbush only, not for the server
+                params.remove(&Param::from("purpose"));
+                if params.remove(&Param::from("tooling")).is_some() {
+                    if let Some(tap_session) = &self.tap_session {
+                        session = tap_session.to_string();
+                    }
+                }
                 params.insert(Param::from("op"), Param::from("eval"));
-                params.insert(
-                    Param::from("code"),
-                    Param::from("(set! *print-namespace-maps* false)"),
-                );
                 params
             }
-            Request::Eval(mut params) => {
-                params.insert(Param::from("op"), Param::from("eval"));
+            Request::LoadFile(mut params) => {
+                self.pending
+                    .push(Eval::load_file(&self.request_cnt.to_string(), &params));
+                params.insert(Param::from("op"), Param::from("load-file"));
+                params
+            }
+            Request::Stdin(mut params) => {
+                params.insert(Param::from("op"), Param::from("stdin"));
                 params
             }
             Request::Exit() => {
+                if let Err(e) = self.stop_taps() {
+                    log::warn!("Unable to stop forwarding tap> values: {}", e);
+                }
                 let mut params = HashMap::new();
                 params.insert(Param::from("op"), Param::from("close"));
                 params
@@ -164,11 +258,8 @@ impl ReplSender for NreplSender {
                 params
             }
         };
-        if !self.session_id.is_empty() {
-            params.insert(
-                Param::from("session"),
-                Param::from(self.session_id.as_str()),
-            );
+        if !session.is_empty() {
+            params.insert(Param::from("session"), Param::from(session.as_str()));
         }
         params.insert(
             Param::from("id"),
@@ -188,24 +279,110 @@ impl ReplSender for NreplSender {
     }
 }
 
+fn get_str<'a>(val: &'a bc::Value, key: &str) -> Option<&'a str> {
+    match val {
+        bc::Value::Map(hm) => match hm.get(&bc::Value::Str(key.into())) {
+            Some(bc::Value::Str(s)) => Some(s),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn statuses(val: &bc::Value) -> Vec<String> {
+    match val {
+        bc::Value::Map(hm) => match hm.get(&bc::Value::Str("status".into())) {
+            Some(bc::Value::List(list)) => list
+                .iter()
+                .filter_map(|s| match s {
+                    bc::Value::Str(s) => Some(s.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        },
+        _ => vec![],
+    }
+}
+
+fn is_done(val: &bc::Value) -> bool {
+    statuses(val).iter().any(|s| s == "done")
+}
+
 impl ReplReceiver for NreplReceiver {
-    fn receive(&mut self) -> Result<Response> {
+    fn receive(&mut self) -> Result<Reply> {
+        if let Some(reply) = self.queue.pop_front() {
+            return Ok(reply);
+        }
+
+        if let Some(i) = self.tap_out.find('\n') {
+            let tap: String = self.tap_out.drain(..=i).collect();
+            return Ok(Response::Tap(tap.trim_end().to_string()).into());
+        }
+
         match bc::parse_bencode(&mut self.reader) {
+            Ok(Some(bencode_val)) if get_str(&bencode_val, "id") == Some(TAP_ID) => {
+                if let Some(out) = get_str(&bencode_val, "out") {
+                    self.tap_out.push_str(out);
+                }
+                Ok(Response::Other("nREPL tap".into()).into())
+            }
+            Ok(Some(bencode_val)) if get_str(&bencode_val, "id") == Some(UNTAP_ID) => {
+                if is_done(&bencode_val) {
+                    // the sender may have stopped waiting already
+                    let _ = self.untapped.send(());
+                }
+                Ok(Response::Other("nREPL untap".into()).into())
+            }
             Ok(Some(bencode_val)) => {
                 log::debug!("Got nREPL message: {}", &bencode_val);
-                let resp = Response::try_from(bencode_val)?;
-                Ok(resp)
+                let eval = match get_str(&bencode_val, "id") {
+                    Some(id) if is_done(&bencode_val) => self.pending.remove(id),
+                    Some(id) => self.pending.get(id),
+                    None => None,
+                };
+                let status = statuses(&bencode_val);
+                let response = match (Response::try_from(bencode_val)?, &eval) {
+                    (Response::Value(value, ns, _, _), Some(eval)) => Response::Value(
+                        value,
+                        ns,
+                        eval.started.elapsed().as_millis() as usize,
+                        eval.code.to_string(),
+                    ),
+                    (response, _) => response,
+                };
+                // statuses sent with an exception or a missing namespace,
+                // e.g. eval-error and done, as pREPL reports them
+                let status: Vec<String> = match response {
+                    Response::Exception(_) => status,
+                    Response::NamespaceNotFound(_) => status
+                        .into_iter()
+                        .filter(|s| s != "namespace-not-found")
+                        .collect(),
+                    _ => vec![],
+                };
+                if !status.is_empty() {
+                    self.queue.push_back(Reply {
+                        eval: eval.clone(),
+                        response: Response::Status(status),
+                    });
+                }
+                Ok(Reply { eval, response })
             }
             Ok(None) => {
                 log::debug!("Got None/Nil from nREPL");
-                Ok(Response::Other("None/Nil Response from nREPL".into()))
+                Ok(Response::Other("None/Nil Response from nREPL".into()).into())
             }
             Err(e) => match e {
-                bc::BencodeError::Eof() => Ok(Response::Eof()),
+                bc::BencodeError::Eof() => Ok(Response::Eof().into()),
                 _ => Err(ReplError::Error(format!("BencodeError: {}", e))),
             },
         }
     }
+
+    fn pending(&self) -> &Pending {
+        &self.pending
+    }
 }
 
 impl TryFrom<bc::Value> for Response {
@@ -226,17 +403,21 @@ impl TryFrom<bc::Value> for Response {
                     log::debug!("nREPL out: {}", s);
                     return Ok(Response::Out(s.to_string()));
                 }
-                if let Some(bc::Value::Str(s)) = hm.get(&bc::Value::Str("ex".into())) {
-                    if let Some(bc::Value::Str(s)) =
-                        hm.get(&bc::Value::Str("nrepl.middleware.caught/throwable".into()))
-                    {
-                        log::debug!("nREPL throwable: {}", s);
-                        let (trace, _) = parse_exception(&s.replace("#error ", ""));
-                        return Ok(Response::Exception(trace, "".to_string()));
-                    } else {
-                        log::debug!("nREPL ex: {}", s);
-                        return Ok(Response::Exception(s.to_string(), "".to_string()));
-                    }
+                if let Some(bc::Value::Str(ex)) = hm.get(&bc::Value::Str("ex".into())) {
+                    let throwable =
+                        hm.get(&bc::Value::Str("nrepl.middleware.caught/throwable".into()));
+                    let info = match throwable {
+                        Some(bc::Value::Str(s)) => {
+                            log::debug!("nREPL throwable: {}", s);
+                            ExceptionInfo::parse(s)
+                        }
+                        _ => None,
+                    };
+
+                    log::debug!("nREPL ex: {}", ex);
+                    return Ok(Response::Exception(
+                        info.unwrap_or_else(|| ExceptionInfo::from_class(ex)),
+                    ));
                 }
                 if let Some(bc::Value::List(list)) = hm.get(&bc::Value::Str("status".into())) {
                     log::debug!("nREPL status: {:?}", list);
@@ -247,6 +428,11 @@ impl TryFrom<bc::Value> for Response {
                             vec.push(s.to_string());
                         }
                     }
+                    if vec.contains(&"namespace-not-found".to_string()) {
+                        if let Some(bc::Value::Str(ns)) = hm.get(&bc::Value::Str("ns".into())) {
+                            return Ok(Response::NamespaceNotFound(ns.to_string()));
+                        }
+                    }
                     return Ok(Response::Status(vec));
                 }
                 if let Some(bc::Value::Str(value)) = hm.get(&bc::Value::Str("value".into())) {
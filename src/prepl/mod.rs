@@ -1,9 +1,81 @@
+use crate::edn;
+use crate::exception::ExceptionInfo;
 use crate::repl;
 use crate::repl::Result;
 use crate::repl::*;
-use edn::parser::Parser;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufReader, BufWriter, Write};
 use std::net::TcpStream;
+use std::path::Path;
+
+mod reader;
+use reader::FormReader;
+
+/// Value returned by the eval wrapper when the requested namespace does not exist.
+const NS_NOT_FOUND: &str = "#redbush/namespace-not-found ";
+
+/// Quotes `s` as a Clojure string literal.
+fn clj_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Wraps `form` so that it is evaluated in namespace `ns`.
+fn in_ns(form: &str, ns: &str) -> String {
+    format!(
+        "(if (clojure.core/find-ns '{ns}) \
+           (do (clojure.core/in-ns '{ns}) {form}) \
+           (clojure.core/tagged-literal 'redbush/namespace-not-found \"{ns}\"))",
+        ns = ns,
+        form = form
+    )
+}
+
+/// Wraps the code of an eval request so that it is read starting from its
+/// line and column in the source file, with `*file*` bound to the file.
+fn wrap_eval(params: &HashMap<repl::Param, repl::Param>) -> Option<String> {
+    let get = |key: &str| match params.get(&repl::Param::from(key)) {
+        Some(repl::Param::Str(s)) if !s.is_empty() => Some(s.as_str()),
+        _ => None,
+    };
+    let get_int = |key: &str| match params.get(&repl::Param::from(key)) {
+        Some(repl::Param::Int(i)) if *i > 0 => *i as usize,
+        _ => 1,
+    };
+
+    let code = get("code")?;
+    let file = get("file").unwrap_or("NO_SOURCE_PATH");
+    let name = Path::new(file)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("NO_SOURCE_FILE");
+    let padded = format!("{}{}", " ".repeat(get_int("column") - 1), code);
+
+    let load = format!(
+        "(clojure.core/let [rdr (clojure.lang.LineNumberingPushbackReader. \
+                                 (java.io.StringReader. {}))] \
+           (.setLineNumber rdr {}) \
+           (clojure.lang.Compiler/load rdr {} {}))",
+        clj_string(&padded),
+        get_int("line"),
+        clj_string(file),
+        clj_string(name)
+    );
+
+    match get("ns") {
+        Some(ns) => Some(in_ns(&load, ns)),
+        None => Some(load),
+    }
+}
+
+/// Code that loads file `contents` like `clojure.core/load` would load `path`.
+fn load_file(contents: &str, path: &str, name: &str) -> String {
+    format!(
+        "(clojure.lang.Compiler/load (java.io.StringReader. {}) {} {})",
+        clj_string(contents),
+        clj_string(path),
+        clj_string(name)
+    )
+}
 
 pub struct PreplSender {
     #[allow(dead_code)]
@@ -12,6 +84,7 @@ pub struct PreplSender {
     port: String,
     pub session_id: String,
     request_cnt: usize,
+    pending: Pending,
     writer: BufWriter<TcpStream>,
 }
 
@@ -31,7 +104,9 @@ pub struct PreplReceiver {
     pub session_id: String,
     #[allow(dead_code)]
     request_cnt: usize,
-    reader: BufReader<TcpStream>,
+    pending: Pending,
+    queue: VecDeque<Reply>,
+    reader: FormReader<BufReader<TcpStream>>,
 }
 
 pub fn new_sender_receiver(host: &str, port: &str) -> Result<(impl ReplSender, impl ReplReceiver)> {
@@ -39,28 +114,27 @@ pub fn new_sender_receiver(host: &str, port: &str) -> Result<(impl ReplSender, i
 
     let stream = TcpStream::connect(format!("{}:{}", host, port))?;
     let stream2 = stream.try_clone()?;
+    let pending = Pending::default();
 
-    let mut sender = PreplSender {
+    let sender = PreplSender {
         session_id: "prepl_default_session".to_string(),
         host: host.to_string(),
         port: port.to_string(),
         request_cnt: 0,
+        pending: pending.clone(),
         writer: BufWriter::new(stream),
     };
 
-    let mut receiver = PreplReceiver {
+    let receiver = PreplReceiver {
         session_id: "".to_string(),
         host: host.to_string(),
         port: port.to_string(),
         request_cnt: 0,
-        reader: BufReader::new(stream2),
+        pending,
+        queue: VecDeque::new(),
+        reader: FormReader::new(BufReader::new(stream2)),
     };
 
-    // Disable keyword namespaces (for edn parser)
-    sender.write_and_flush(b"(set! *print-namespace-maps* false)\n")?;
-    let mut _buf = "".to_string();
-    receiver.reader.read_line(&mut _buf)?;
-
     Ok((sender, receiver))
 }
 
@@ -70,22 +144,45 @@ impl ReplSender for PreplSender {
 
         match req {
             Request::Eval(params) => {
-                let code = match params.get(&repl::Param::Str("code".into())) {
-                    Some(repl::Param::Str(s)) => format!("{}\n", s),
-                    _ => "".to_string(),
+                let code = match wrap_eval(&params) {
+                    Some(code) => format!("{}\n", code),
+                    None => "".to_string(),
                 };
 
                 if !code.is_empty() {
                     log::debug!("Sending code to PREPL: {}", &code);
 
                     self.write_and_flush(code.as_bytes())?;
+                    self.pending.push(Eval {
+                        form: code.trim().to_string(),
+                        ..Eval::new(&self.request_cnt.to_string(), &params)
+                    });
                     self.request_cnt += 1;
                 }
             }
+            Request::LoadFile(params) => {
+                let get = |key: &str| match params.get(&repl::Param::from(key)) {
+                    Some(repl::Param::Str(s)) => s.to_string(),
+                    _ => "".to_string(),
+                };
+                let code = load_file(&get("file"), &get("file-path"), &get("file-name"));
+
+                log::debug!("Sending load-file to PREPL: {}", get("file-path"));
+
+                self.write_and_flush(format!("{}\n", code).as_bytes())?;
+                self.pending.push(Eval {
+                    form: code,
+                    ..Eval::load_file(&self.request_cnt.to_string(), &params)
+                });
+                self.request_cnt += 1;
+            }
             Request::Exit() => {
                 log::debug!("Sending exit to PREPL");
                 self.write_and_flush(b":repl/quit\n")?;
             }
+            // input is read from the socket like forms are, so there is no
+            // way to give it to a running evaluation
+            Request::Stdin(_) => return Err(ReplError::from("stdin not supported on pREPL")),
             _ => (),
         };
 
@@ -98,80 +195,119 @@ impl ReplSender for PreplSender {
 }
 
 impl ReplReceiver for PreplReceiver {
-    fn receive(&mut self) -> Result<Response> {
-        log::debug!("Reading pREPL response");
+    fn receive(&mut self) -> Result<Reply> {
+        if let Some(reply) = self.queue.pop_front() {
+            return Ok(reply);
+        }
+
+        let (response, ret_form) = self.read_response()?;
+
+        match ret_form {
+            // ':ret' ends the evaluation of a form, pREPL has no statuses
+            Some(form) => {
+                let eval = match self.pending.take(|e| e.form == form) {
+                    Some(eval) => Some(eval),
+                    None => {
+                        log::debug!("No pending eval for pREPL form: {}", &form);
+                        self.pending.pop_front()
+                    }
+                };
+                let response = match (response, &eval) {
+                    (Response::Value(val, ns, ms, _), Some(eval)) => {
+                        Response::Value(val, ns, ms, eval.code.to_string())
+                    }
+                    (response, _) => response,
+                };
+                // statuses as nREPL would report them
+                let status = match response {
+                    Response::Exception(_) => vec!["eval-error".to_string(), "done".to_string()],
+                    _ => vec!["done".to_string()],
+                };
+                self.queue.push_back(Reply {
+                    eval: eval.clone(),
+                    response: Response::Status(status),
+                });
 
-        let mut resp = "".to_string();
-        self.reader.read_line(&mut resp)?;
+                Ok(Reply { eval, response })
+            }
+            None => {
+                let eval = match response {
+                    Response::Out(_) | Response::Err(_) => self.pending.front(),
+                    _ => None,
+                };
 
-        let mut parser = Parser::new(&resp);
-        let edn_val = parser.read();
+                Ok(Reply { eval, response })
+            }
+        }
+    }
 
+    fn pending(&self) -> &Pending {
+        &self.pending
+    }
+}
+
+impl PreplReceiver {
+    /// Reads the next pREPL message, along with its `:form` for `:ret` messages.
+    fn read_response(&mut self) -> Result<(Response, Option<String>)> {
+        log::debug!("Reading pREPL response");
+
+        let resp = match self.reader.read_form()? {
+            Some(form) => form,
+            None => return Ok((Response::Eof(), None)),
+        };
+
+        let edn_val = edn::parse(&resp);
         log::debug!("pREPL edn: {:?}", &edn_val);
+
+        let string = |msg: &edn::Value, key: &str| {
+            msg.get(key)
+                .and_then(edn::Value::as_str)
+                .unwrap_or("")
+                .to_string()
+        };
+
         match edn_val {
-            Some(Ok(edn::Value::Map(map))) => {
-                let tag = map.get(&edn::Value::Keyword("tag".into())).unwrap();
-                match tag {
-                    edn::Value::Keyword(key) => match key.to_string().as_str() {
-                        "ret" => {
-                            let val = match map.get(&edn::Value::Keyword("val".into())) {
-                                Some(edn::Value::String(s)) => s.to_owned(),
-                                _ => "".to_string(),
-                            };
-                            let ns = match map.get(&edn::Value::Keyword("ns".into())) {
-                                Some(edn::Value::String(s)) => s.to_owned(),
-                                _ => "".to_string(),
-                            };
-                            let ms = match map.get(&edn::Value::Keyword("ms".into())) {
-                                Some(edn::Value::Integer(i)) => *i,
-                                _ => 0i64,
-                            };
-                            let form = match map.get(&edn::Value::Keyword("form".into())) {
-                                Some(edn::Value::String(s)) => s.to_owned(),
-                                _ => "".to_string(),
-                            };
-                            let exception = match map.get(&edn::Value::Keyword("exception".into()))
-                            {
-                                Some(edn::Value::Boolean(b)) => *b,
-                                _ => false,
-                            };
-                            if exception {
-                                log::debug!("EXCEPTION: {}", &val);
-                                let (trace, err) = parse_exception(&val);
-                                Ok(Response::Exception(trace, err))
-                            } else {
-                                Ok(Response::Value(val, ns, ms as usize, form))
+            Ok(msg @ edn::Value::Map(_)) => match msg.get("tag").and_then(edn::Value::as_name) {
+                Some("ret") => {
+                    let val = string(&msg, "val");
+                    let ns = string(&msg, "ns");
+                    let ms = msg.get("ms").and_then(edn::Value::as_i64).unwrap_or(0);
+                    let form = string(&msg, "form");
+                    let exception = msg
+                        .get("exception")
+                        .and_then(edn::Value::as_bool)
+                        .unwrap_or(false);
+
+                    let response = if let Some(ns) = val.strip_prefix(NS_NOT_FOUND) {
+                        Response::NamespaceNotFound(ns.trim_matches('"').to_string())
+                    } else if exception {
+                        log::debug!("EXCEPTION: {}", &val);
+                        Response::Exception(ExceptionInfo::parse(&val).unwrap_or_else(|| {
+                            ExceptionInfo {
+                                cause: val.to_string(),
+                                ..ExceptionInfo::from_class("")
                             }
-                        }
-                        "out" => {
-                            let out = match map.get(&edn::Value::Keyword("val".into())) {
-                                Some(edn::Value::String(s)) => s.to_owned(),
-                                _ => "".to_string(),
-                            };
-
-                            Ok(Response::Out(out))
-                        }
-                        "err" => {
-                            let out = match map.get(&edn::Value::Keyword("val".into())) {
-                                Some(edn::Value::String(s)) => s.to_owned(),
-                                _ => "".to_string(),
-                            };
-
-                            Ok(Response::Out(out))
-                        }
-                        _ => Ok(Response::Other(key.to_string())),
-                    },
-                    _ => Ok(Response::Other("".to_string())),
+                        }))
+                    } else {
+                        Response::Value(val, ns, ms as usize, form.to_string())
+                    };
+                    Ok((response, Some(form)))
                 }
+                Some("out") => Ok((Response::Out(string(&msg, "val")), None)),
+                Some("err") => Ok((Response::Err(string(&msg, "val")), None)),
+                Some("tap") => Ok((Response::Tap(string(&msg, "val")), None)),
+                Some(tag) => Ok((Response::Other(tag.to_string()), None)),
+                None => Ok((Response::Other("".to_string()), None)),
+            },
+            // skip malformed and unexpected input instead of giving up on the stream
+            Err(e) => {
+                log::warn!("Failed to parse pREPL message: {:?}: {}", e, &resp);
+                Ok((Response::Other(resp), None))
+            }
+            Ok(x) => {
+                log::warn!("Unexpected pREPL message: {:?}", x);
+                Ok((Response::Other(resp), None))
             }
-            Some(Err(e)) => Err(ReplError::Error(format!("EDN parser Error: {:?}", e))),
-            Some(x) => Err(ReplError::Error(format!(
-                "EDN parser Error: unexpected response from pREPL: {:?}",
-                x
-            ))),
-            None => Err(ReplError::Error(
-                "EDN parser Error: trying to parse empty string".into(),
-            )),
         }
     }
 }
@@ -0,0 +1,241 @@
+//! Splits the pREPL output stream into top-level EDN forms.
+//!
+//! pREPL messages are not guaranteed to be one per line: a custom `valf` or
+//! a pretty printer may spread a message over several lines, and several
+//! messages may arrive on one line. `FormReader` tracks strings, character
+//! literals, comments and nesting so that it always yields whole forms. A
+//! malformed message never ends, so it is dropped when the next message
+//! starts on a new line.
+
+use crate::clojure::{is_terminator, is_whitespace};
+use std::io::{self, BufRead};
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Normal,
+    Str,
+    StrEscape,
+    Comment,
+    CharStart,
+}
+
+/// Start of a pREPL message. Each message is printed on a line of its own.
+const MESSAGE_START: &[u8] = b"{:tag ";
+
+pub struct FormReader<R: BufRead> {
+    reader: R,
+    /// line being read and the position of the next byte in it
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> FormReader<R> {
+    pub fn new(reader: R) -> FormReader<R> {
+        FormReader {
+            reader,
+            line: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Reads the text of the next top-level form, or `None` at end of stream.
+    /// Unbalanced closing delimiters and forms discarded with `#_` between
+    /// forms are dropped.
+    pub fn read_form(&mut self) -> io::Result<Option<String>> {
+        // forms still to discard, `#_ #_ a b` discarding two
+        let mut discard = 0;
+        loop {
+            let text = match self.read_text()? {
+                Some(text) => text,
+                None => return Ok(None),
+            };
+
+            // the discard prefixes are read together with the first form
+            // they apply to
+            let mut rest = text.as_str();
+            while let Some(after) = rest.strip_prefix("#_") {
+                discard += 1;
+                rest = after.trim_start_matches(is_whitespace);
+            }
+            if discard == 0 {
+                return Ok(Some(text));
+            }
+            if !rest.is_empty() {
+                discard -= 1;
+            }
+        }
+    }
+
+    /// Reads the text of the next top-level form including its `#_` prefixes.
+    /// A form left unfinished by an unterminated string or unclosed
+    /// delimiters is dropped when a new message starts on the next line.
+    fn read_text(&mut self) -> io::Result<Option<String>> {
+        let mut form: Vec<u8> = vec![];
+        let mut state = State::Normal;
+        let mut depth = 0usize;
+        // reading a symbol, keyword, number or dispatch token
+        let mut in_token = false;
+        // the token is a top-level tag or dispatch prefix, e.g. `#inst`
+        let mut is_tag = false;
+
+        loop {
+            if self.pos == self.line.len() {
+                self.line.clear();
+                self.pos = 0;
+                if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+                    let text = String::from_utf8_lossy(&form).trim().to_string();
+                    return Ok(if text.is_empty() { None } else { Some(text) });
+                }
+
+                let unfinished = depth > 0 || matches!(state, State::Str | State::StrEscape);
+                if unfinished && self.line.starts_with(MESSAGE_START) {
+                    log::warn!(
+                        "Dropping malformed pREPL message: {}",
+                        String::from_utf8_lossy(&form)
+                    );
+                    form.clear();
+                    state = State::Normal;
+                    depth = 0;
+                    in_token = false;
+                    is_tag = false;
+                }
+            }
+
+            let start = self.pos;
+            let mut done = false;
+
+            while self.pos < self.line.len() {
+                let b = self.line[self.pos];
+                self.pos += 1;
+
+                match state {
+                    State::Str => match b {
+                        b'\\' => state = State::StrEscape,
+                        b'"' => {
+                            state = State::Normal;
+                            done = depth == 0;
+                        }
+                        _ => (),
+                    },
+                    State::StrEscape => state = State::Str,
+                    State::Comment => {
+                        if b == b'\n' {
+                            state = State::Normal;
+                        }
+                    }
+                    State::CharStart => state = State::Normal,
+                    State::Normal => {
+                        if in_token {
+                            if !is_terminator(b.into()) {
+                                continue;
+                            }
+                            in_token = false;
+                            if depth == 0 && !is_tag {
+                                // the terminator may start the next form
+                                if !is_whitespace(b.into()) {
+                                    self.pos -= 1;
+                                }
+                                done = true;
+                                break;
+                            }
+                            is_tag = false;
+                        }
+
+                        match b {
+                            b';' => state = State::Comment,
+                            b'"' => state = State::Str,
+                            b'(' | b'[' | b'{' => depth += 1,
+                            b')' | b']' | b'}' => {
+                                if depth > 0 {
+                                    depth -= 1;
+                                    done = depth == 0;
+                                }
+                            }
+                            b'\\' => {
+                                in_token = true;
+                                state = State::CharStart;
+                            }
+                            b'#' => {
+                                in_token = true;
+                                is_tag = depth == 0;
+                            }
+                            b'\'' | b'`' | b'~' | b'@' | b'^' => (),
+                            _ if is_whitespace(b.into()) => (),
+                            _ => in_token = true,
+                        }
+                    }
+                }
+
+                if done {
+                    break;
+                }
+            }
+
+            form.extend_from_slice(&self.line[start..self.pos]);
+
+            if done {
+                let text = String::from_utf8_lossy(&form);
+                let text = text.trim_start_matches(|c: char| {
+                    c == ')' || c == ']' || c == '}' || c.is_whitespace() || c == ','
+                });
+                return Ok(Some(text.trim_end().to_string()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forms(src: &str) -> Vec<String> {
+        let mut reader = FormReader::new(src.as_bytes());
+        let mut forms = vec![];
+        while let Some(form) = reader.read_form().unwrap() {
+            forms.push(form);
+        }
+        forms
+    }
+
+    #[test]
+    fn forms_across_lines() {
+        assert_eq!(
+            forms("{:tag :ret\n :val \"(\"}{:tag :out} :k 12 \\) x"),
+            vec![
+                "{:tag :ret\n :val \"(\"}",
+                "{:tag :out}",
+                ":k",
+                "12",
+                "\\)",
+                "x"
+            ]
+        );
+        assert_eq!(forms("#inst \"2020\" ) [1]"), vec!["#inst \"2020\"", "[1]"]);
+    }
+
+    #[test]
+    fn discarded_forms_are_skipped() {
+        assert_eq!(forms("#_ x {:a 1}"), vec!["{:a 1}"]);
+        assert_eq!(forms("#_(a b) 1"), vec!["1"]);
+        assert_eq!(forms("#_ #_ a b c"), vec!["c"]);
+        assert_eq!(forms("[#_ a b]"), vec!["[#_ a b]"]);
+        assert_eq!(forms("1 #_"), vec!["1"]);
+    }
+
+    #[test]
+    fn malformed_messages_are_dropped() {
+        assert_eq!(
+            forms("{:tag :out :val \"abc}\n{:tag :ret :val \"1\"}\n{:tag :out :val \"x\"}\n"),
+            vec!["{:tag :ret :val \"1\"}", "{:tag :out :val \"x\"}"]
+        );
+        assert_eq!(
+            forms("{:tag :ret :val [1 2\n{:tag :ret :val \"1\"}\n"),
+            vec!["{:tag :ret :val \"1\"}"]
+        );
+        // pretty printed messages continue on indented lines
+        assert_eq!(
+            forms("{:tag :ret\n :val {:tag :x}}\n"),
+            vec!["{:tag :ret\n :val {:tag :x}}"]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Stack trace frames as Neovim quickfix items.
+//!
+//! Frames of project files are resolved against the source roots of the
+//! project. The rest are looked up from the classpath of the REPL with
+//! `clojure.java.io/resource`, which gives `file:` URLs for directories and
+//! `jar:file:` URLs for libraries.
+
+use crate::edn;
+use crate::exception::{ExceptionInfo, Frame};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Classpath-relative path of the source file of `frame`, e.g.
+/// `clojure/core.clj` for `clojure.core$map$fn__5862` in `core.clj`.
+pub fn resource(frame: &Frame) -> Option<String> {
+    if frame.file.is_empty() || frame.file.starts_with("NO_SOURCE") {
+        return None;
+    }
+
+    let class = frame.class.split('$').next().unwrap_or("");
+    match class.rfind('.') {
+        Some(i) => Some(format!("{}/{}", class[..i].replace('.', "/"), frame.file)),
+        None => Some(frame.file.to_string()),
+    }
+}
+
+/// Code that returns the URLs of `resources` as a vector of strings, `nil`
+/// for those not on the classpath.
+pub fn lookup_code(resources: &[String]) -> String {
+    let paths: Vec<String> = resources
+        .iter()
+        .map(|r| edn::Value::String(r.to_string()).to_string())
+        .collect();
+
+    format!(
+        "(clojure.core/mapv \
+           (clojure.core/fn [p] (clojure.core/some-> (clojure.java.io/resource p) clojure.core/str)) \
+           [{}])",
+        paths.join(" ")
+    )
+}
+
+/// Code that returns the URL of `resource` and `line` as a vector.
+pub fn goto_code(resource: &str, line: i64) -> String {
+    format!(
+        "[(clojure.core/some-> (clojure.java.io/resource {}) clojure.core/str) {}]",
+        edn::Value::String(resource.to_string()),
+        line
+    )
+}
+
+/// Resources of the frames of `info` that need to be looked up from the
+/// REPL, i.e. are not found under `roots`.
+pub fn unresolved(info: &ExceptionInfo, roots: &[PathBuf]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    info.trace
+        .iter()
+        .filter_map(resource)
+        .filter(|r| find(r, roots).is_none())
+        .filter(|r| seen.insert(r.to_string()))
+        .collect()
+}
+
+pub fn find(resource: &str, roots: &[PathBuf]) -> Option<PathBuf> {
+    roots
+        .iter()
+        .map(|root| root.join(resource))
+        .find(|path| path.is_file())
+}
+
+/// File name Neovim can open for a resource URL. Files in jars are opened
+/// with the zip plugin.
+pub fn url_to_file(url: &str) -> Option<String> {
+    if let Some(jar) = url.strip_prefix("jar:file:") {
+        let (jar, entry) = jar.split_once("!/")?;
+        Some(format!("zipfile:{}::{}", jar, entry))
+    } else {
+        url.strip_prefix("file:").map(String::from)
+    }
+}
+
+/// Quickfix items for the frames of `info`, `urls` being the looked up
+/// resources as returned by `lookup_code`. Frames without a file are
+/// listed with their name only.
+pub fn items(
+    info: &ExceptionInfo,
+    roots: &[PathBuf],
+    resources: &[String],
+    urls: &edn::Value,
+) -> Vec<neovim_lib::Value> {
+    let urls = urls.as_slice().unwrap_or(&[]);
+    let looked_up = |resource: &str| {
+        let i = resources.iter().position(|r| r == resource)?;
+        urls.get(i)?.as_str().and_then(url_to_file)
+    };
+
+    info.trace
+        .iter()
+        .map(|frame| {
+            let file = resource(frame).and_then(|r| {
+                find(&r, roots)
+                    .map(|path| path.to_string_lossy().to_string())
+                    .or_else(|| looked_up(&r))
+            });
+
+            let mut item: Vec<(neovim_lib::Value, neovim_lib::Value)> = vec![
+                ("text".into(), frame.name.as_str().into()),
+                ("lnum".into(), frame.line.into()),
+            ];
+            match file {
+                Some(file) => item.push(("filename".into(), file.into())),
+                None => item.push(("valid".into(), false.into())),
+            }
+
+            neovim_lib::Value::Map(item)
+        })
+        .collect()
+}
+
+/// Source roots `paths` relative to the working directory `cwd`.
+pub fn roots(cwd: &str, paths: &[&str]) -> Vec<PathBuf> {
+    paths.iter().map(|p| Path::new(cwd).join(p)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(trace: &str) -> ExceptionInfo {
+        let val = edn::parse(&format!(
+            "{{:via [{{:type java.lang.Exception :message \"x\"}}] :trace {}}}",
+            trace
+        ))
+        .unwrap();
+        ExceptionInfo::from_edn(&val).unwrap()
+    }
+
+    #[test]
+    fn resources_of_frames() {
+        let info = info(
+            r#"[[my_app.core_test$foo$fn__12 invoke "core_test.clj" 3]
+                [clojure.core$map$fn__5862 invoke "core.clj" 2745]
+                [user$eval1 invokeStatic "NO_SOURCE_FILE" 1]
+                [java.lang.Thread run "Thread.java" 829]
+                [foo.Bar baz nil -1]
+                [single invoke "single.clj" 1]]"#,
+        );
+        let resources: Vec<Option<String>> = info.trace.iter().map(resource).collect();
+
+        assert_eq!(
+            resources,
+            vec![
+                Some("my_app/core_test.clj".to_string()),
+                Some("clojure/core.clj".to_string()),
+                None,
+                Some("java/lang/Thread.java".to_string()),
+                None,
+                Some("single.clj".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unresolved_resources_are_unique() {
+        let info = info(
+            r#"[[clojure.core$map invoke "core.clj" 1]
+                [clojure.lang.LazySeq sval "LazySeq.java" 42]
+                [clojure.core$seq invokeStatic "core.clj" 2]
+                [clojure.lang.LazySeq seq "LazySeq.java" 51]]"#,
+        );
+
+        assert_eq!(
+            unresolved(&info, &[]),
+            vec!["clojure/core.clj", "clojure/lang/LazySeq.java"]
+        );
+    }
+
+    #[test]
+    fn urls_to_files() {
+        assert_eq!(
+            url_to_file("jar:file:/m2/clojure-1.11.1.jar!/clojure/core.clj"),
+            Some("zipfile:/m2/clojure-1.11.1.jar::clojure/core.clj".to_string())
+        );
+        assert_eq!(
+            url_to_file("file:/home/u/app/src/app/core.clj"),
+            Some("/home/u/app/src/app/core.clj".to_string())
+        );
+        assert_eq!(url_to_file("jar:file:/m2/broken.jar"), None);
+        assert_eq!(url_to_file("http://example.com/core.clj"), None);
+    }
+
+    #[test]
+    fn items_of_frames() {
+        let info = info(
+            r#"[[clojure.core$map invoke "core.clj" 1]
+                [user$eval1 invoke "NO_SOURCE_FILE" 1]]"#,
+        );
+        let resources = unresolved(&info, &[]);
+        let urls = edn::parse(r#"["jar:file:/m2/clojure.jar!/clojure/core.clj"]"#).unwrap();
+        let items = items(&info, &[], &resources, &urls);
+
+        let get = |item: &neovim_lib::Value, key: &str| match item {
+            neovim_lib::Value::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| k.as_str() == Some(key))
+                .map(|(_, v)| v.clone()),
+            _ => None,
+        };
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            get(&items[0], "filename"),
+            Some("zipfile:/m2/clojure.jar::clojure/core.clj".into())
+        );
+        assert_eq!(get(&items[0], "lnum"), Some(1.into()));
+        assert_eq!(get(&items[1], "filename"), None);
+        assert_eq!(get(&items[1], "valid"), Some(false.into()));
+    }
+}
@@ -1,6 +1,8 @@
-use edn::parser::Parser;
-use std::collections::HashMap;
+use crate::exception::ExceptionInfo;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 pub type Result<T> = std::result::Result<T, ReplError>;
 
@@ -31,7 +33,7 @@ impl Display for ReplError {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Param {
     Str(String),
     Int(i32),
@@ -46,78 +48,158 @@ impl From<&str> for Param {
 #[derive(Debug, Eq, PartialEq)]
 pub enum Request {
     Eval(HashMap<Param, Param>),
+    LoadFile(HashMap<Param, Param>),
     Interrupt(HashMap<Param, Param>),
+    /// input for an evaluation waiting for it, empty for end of input
+    Stdin(HashMap<Param, Param>),
     NewSession(),
-    DisableNsMaps(),
     Exit(),
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum Response {
     //    value   ns      ms     form
     Value(String, String, usize, String),
     Err(String),
     Out(String),
-    Exception(String, String),
+    Exception(ExceptionInfo),
     Status(Vec<String>),
     NewSession(String),
+    NamespaceNotFound(String),
+    /// value given to `tap>`, printed
+    Tap(String),
     Eof(),
     Other(String),
 }
 
-pub trait ReplSender {
-    fn session_id(&self) -> String;
-    fn send(&mut self, req: Request) -> Result<()>;
+/// Evaluation request waiting for its results from the REPL.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Eval {
+    pub id: String,
+    pub code: String,
+    pub ns: String,
+    /// file and 1-based line of `code`, line 0 standing for the whole file
+    pub file: String,
+    pub line: i64,
+    /// form as written to the REPL, when it differs from `code`
+    pub form: String,
+    /// why redbush itself evaluates the code, empty for user evaluations
+    pub purpose: String,
+    pub started: Instant,
 }
 
-pub trait ReplReceiver: Send + Sync + 'static {
-    fn receive(&mut self) -> Result<Response>;
-}
-
-pub fn parse_exception(edn_s: &str) -> (String, String) {
-    log::debug!("Parsing exception: {}", &edn_s);
-
-    let mut errmsg = "".to_string();
-    let mut trace = "".to_string();
-    let mut parser = Parser::new(&edn_s);
-    let edn_val = parser.read().unwrap();
-
-    if let Ok(edn::Value::Map(map)) = edn_val {
-        if let edn::Value::Vector(vec) = map.get(&edn::Value::Keyword("via".to_string())).unwrap() {
-            for val in vec.iter() {
-                if let edn::Value::Map(m) = val {
-                    if let Some(edn::Value::String(s)) =
-                        m.get(&edn::Value::Keyword("message".to_string()))
-                    {
-                        errmsg.push_str(s);
-                        errmsg.push('\n');
-                    }
-                }
-            }
+impl Eval {
+    pub fn new(id: &str, params: &HashMap<Param, Param>) -> Eval {
+        let get = |key: &str| match params.get(&Param::from(key)) {
+            Some(Param::Str(s)) => s.to_string(),
+            _ => "".to_string(),
+        };
+
+        Eval {
+            id: id.to_string(),
+            code: get("code"),
+            ns: get("ns"),
+            file: get("file"),
+            line: match params.get(&Param::from("line")) {
+                Some(Param::Int(i)) => *i as i64,
+                _ => 0,
+            },
+            form: "".to_string(),
+            purpose: get("purpose"),
+            started: Instant::now(),
         }
-        if let edn::Value::Vector(vec) = map.get(&edn::Value::Keyword("trace".to_string())).unwrap()
-        {
-            for val in vec.iter() {
-                if let edn::Value::Vector(v) = val {
-                    for val in v.iter() {
-                        if let edn::Value::Symbol(sym) = val {
-                            trace.push_str(sym);
-                            trace.push(' ');
-                        } else if let edn::Value::String(s) = val {
-                            trace.push_str(s);
-                            trace.push(' ');
-                        } else if let edn::Value::Integer(i) = val {
-                            trace.push_str(&i.to_string());
-                            trace.push(' ');
-                        }
-                    }
-
-                    trace = trace.trim().to_string();
-                    trace.push('\n');
-                }
-            }
+    }
+
+    /// Evaluation for loading a file with `load-file` params.
+    pub fn load_file(id: &str, params: &HashMap<Param, Param>) -> Eval {
+        let path = match params.get(&Param::from("file-path")) {
+            Some(Param::Str(s)) => s.to_string(),
+            _ => "".to_string(),
+        };
+
+        Eval {
+            code: format!("(load-file \"{}\")", path),
+            file: path,
+            line: 0,
+            ..Eval::new(id, params)
         }
     }
+}
+
+/// Evaluations sent but not yet completed, oldest first. Shared between
+/// the sender and the receiver of a REPL connection.
+#[derive(Debug, Clone, Default)]
+pub struct Pending(Arc<Mutex<VecDeque<Eval>>>);
+
+impl Pending {
+    pub fn push(&self, eval: Eval) {
+        self.0.lock().unwrap().push_back(eval);
+    }
+
+    pub fn get(&self, id: &str) -> Option<Eval> {
+        self.0.lock().unwrap().iter().find(|e| e.id == id).cloned()
+    }
+
+    pub fn front(&self) -> Option<Eval> {
+        self.0.lock().unwrap().front().cloned()
+    }
 
-    (trace, errmsg)
+    /// Removes the oldest evaluation matching `pred`.
+    pub fn take(&self, pred: impl Fn(&Eval) -> bool) -> Option<Eval> {
+        let mut evals = self.0.lock().unwrap();
+        let idx = evals.iter().position(pred)?;
+        evals.remove(idx)
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Eval> {
+        self.take(|e| e.id == id)
+    }
+
+    pub fn pop_front(&self) -> Option<Eval> {
+        self.0.lock().unwrap().pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/// Response from the REPL together with the evaluation that caused it.
+#[derive(Debug, PartialEq)]
+pub struct Reply {
+    pub eval: Option<Eval>,
+    pub response: Response,
+}
+
+impl From<Response> for Reply {
+    fn from(response: Response) -> Reply {
+        Reply {
+            eval: None,
+            response,
+        }
+    }
+}
+
+/// Message for the user about status flag `status`, `None` for flags that
+/// need no attention such as `done`.
+pub fn status_message(status: &str) -> Option<&'static str> {
+    match status {
+        "eval-error" => Some("Evaluation failed"),
+        "interrupted" => Some("Evaluation interrupted"),
+        "namespace-not-found" => Some("Namespace not found"),
+        "unknown-op" => Some("Operation not supported by the REPL"),
+        "unknown-session" => Some("Unknown REPL session, restart redbush"),
+        "need-input" => Some("Evaluation is waiting for input"),
+        _ => None,
+    }
+}
+
+pub trait ReplSender {
+    fn session_id(&self) -> String;
+    fn send(&mut self, req: Request) -> Result<()>;
+}
+
+pub trait ReplReceiver: Send + Sync + 'static {
+    fn receive(&mut self) -> Result<Reply>;
+    fn pending(&self) -> &Pending;
 }